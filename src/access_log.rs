@@ -0,0 +1,442 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+use crate::{CommandObserver, CommandRecord};
+
+/// When to start a new access log file. The old file is renamed to `<path>.<UTC time>`, e.g. `access.log.20240131T120000Z`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Rotation {
+    /// rotate before the file grows over this size. 0 for no limit.
+    pub max_size: u64,
+    /// rotate when a new interval starts, counted from the unix epoch, e.g. every day at 00:00 UTC
+    pub interval: Option<Duration>,
+    /// number of rotated files to keep. 0 keeps all of them.
+    pub max_files: usize,
+}
+
+/// Lines waiting for the disk. More are dropped.
+const MAX_QUEUED_LINES: usize = 16 * 1024;
+
+enum Message {
+    Line(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// JSON lines log of every served command, one object per line.
+/// The file is written by a background task, so serving never waits for the disk.
+/// Lines are dropped while the disk is too far behind.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    sender: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    /// open the file for appending and start the writer task. must be called inside the tokio runtime.
+    pub async fn open(path: impl AsRef<Path>, rotation: Rotation) -> io::Result<Self> {
+        let mut writer = Writer::open(path.as_ref().to_path_buf(), rotation).await?;
+        let (sender, mut receiver) = mpsc::channel::<Message>(MAX_QUEUED_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = dropped.clone();
+        tokio::spawn(async move {
+            let mut reported = 0;
+            loop {
+                // a quiet server still starts a new file when the interval ends
+                let message = tokio::select! {
+                    message = receiver.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    _ = sleep_until_next_interval(rotation.interval) => {
+                        if let Err(e) = writer.rotate_if_due(SystemTime::now(), 0).await {
+                            error!(path = %writer.path.display(), error = %e, "rotate access log error");
+                        }
+                        continue;
+                    }
+                };
+                // write everything queued, then flush once
+                let mut next = Some(message);
+                let mut flushed = Vec::new();
+                while let Some(message) = next {
+                    match message {
+                        Message::Line(line) => {
+                            if let Err(e) = writer.write(&line).await {
+                                error!(path = %writer.path.display(), error = %e, "write access log error");
+                            }
+                        }
+                        Message::Flush(done) => flushed.push(done),
+                    }
+                    next = receiver.try_recv().ok();
+                }
+                if let Err(e) = writer.file.flush().await {
+                    error!(path = %writer.path.display(), error = %e, "flush access log error");
+                }
+                for done in flushed {
+                    let _ = done.send(());
+                }
+                let dropped = writer_dropped.load(Ordering::Relaxed);
+                if dropped > reported {
+                    warn!(path = %writer.path.display(), dropped = dropped - reported, "access log lines dropped. the disk is too slow");
+                    reported = dropped;
+                }
+            }
+        });
+        Ok(Self { sender, dropped })
+    }
+
+    /// number of lines dropped because the disk was too slow
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// an observer of the sessions of one client
+    pub fn observer(&self, listener: &str, client: &str) -> Arc<dyn CommandObserver> {
+        Arc::new(AccessLogObserver {
+            log: self.clone(),
+            listener: listener.to_string(),
+            client: client.to_string(),
+        })
+    }
+
+    /// wait until everything logged so far is written to the file
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    fn log(&self, listener: &str, client: &str, record: &CommandRecord) {
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_micros(record.started_at).to_string(),
+            listener,
+            client,
+            command: record.kind.as_str(),
+            file_type: record.file_type.as_ref().map(|t| t.to_ext()),
+            guid: record.guid.map(|guid| guid.to_hex_string()),
+            hash: record.hash.map(|hash| hash.to_hex_string()),
+            result: record.outcome.as_str(),
+            error: record.outcome.error(),
+            bytes: record.bytes,
+            duration_ms: record.duration.as_secs_f64() * 1000.0,
+        };
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(error = %e, "serialize access log entry error");
+                return;
+            }
+        };
+        line.push(b'\n');
+        self.send(line);
+    }
+
+    /// queue a line. serving never waits for the writer, so the line is dropped if the queue is full.
+    fn send(&self, line: Vec<u8>) {
+        match self.sender.try_send(Message::Line(line)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // the writer task never stops on its own
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// one line of the access log
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    listener: &'a str,
+    client: &'a str,
+    command: &'static str,
+    file_type: Option<&'a str>,
+    guid: Option<String>,
+    hash: Option<String>,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    bytes: u64,
+    duration_ms: f64,
+}
+
+#[derive(Debug)]
+struct AccessLogObserver {
+    log: AccessLog,
+    listener: String,
+    client: String,
+}
+
+impl CommandObserver for AccessLogObserver {
+    fn on_command(&self, record: CommandRecord) {
+        self.log.log(&self.listener, &self.client, &record);
+    }
+}
+
+/// the open file and its rotation state
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    file: BufWriter<File>,
+    size: u64,
+    /// the interval the file was written in
+    interval_index: u64,
+}
+
+impl Writer {
+    async fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        let meta = file.metadata().await?;
+        // a file left from an earlier interval is rotated before the first write
+        let modified = if meta.len() > 0 { meta.modified().unwrap_or_else(|_| SystemTime::now()) } else { SystemTime::now() };
+        Ok(Self {
+            interval_index: interval_index(rotation.interval, modified),
+            size: meta.len(),
+            file: BufWriter::new(file),
+            path,
+            rotation,
+        })
+    }
+
+    async fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        self.rotate_if_due(SystemTime::now(), line.len() as u64).await?;
+        self.file.write_all(line).await?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// start a new file if the interval has ended, or if `next_line` bytes would make the file too large.
+    /// an empty file is kept.
+    async fn rotate_if_due(&mut self, now: SystemTime, next_line: u64) -> io::Result<()> {
+        let interval_index = interval_index(self.rotation.interval, now);
+        let too_large = self.rotation.max_size > 0 && self.size + next_line > self.rotation.max_size;
+        if self.size > 0 && (too_large || interval_index != self.interval_index) {
+            self.rotate(now).await?;
+        }
+        self.interval_index = interval_index;
+        Ok(())
+    }
+
+    async fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        self.file.flush().await?;
+        let stamp: String = humantime::format_rfc3339_seconds(now).to_string().chars().filter(|c| *c != '-' && *c != ':').collect();
+        let mut rotated = suffixed(&self.path, &stamp);
+        let mut n = 1;
+        while tokio::fs::try_exists(&rotated).await? {
+            rotated = suffixed(&self.path, &format!("{}.{}", stamp, n));
+            n += 1;
+        }
+        tokio::fs::rename(&self.path, &rotated).await?;
+        let file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        if self.rotation.max_files > 0 {
+            if let Err(e) = self.remove_old_files().await {
+                error!(path = %self.path.display(), error = %e, "remove old access log files error");
+            }
+        }
+        Ok(())
+    }
+
+    /// keep the newest `max_files` rotated files
+    async fn remove_old_files(&self) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(()),
+        };
+        let mut rotated = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(stamp) = name.strip_prefix(&prefix) {
+                if stamp.starts_with(|c: char| c.is_ascii_digit()) {
+                    rotated.push(name);
+                }
+            }
+        }
+        // the UTC time sorts oldest first
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.rotation.max_files);
+        for name in &rotated[..excess] {
+            tokio::fs::remove_file(dir.join(name)).await?;
+        }
+        Ok(())
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// sleep until the next interval starts. never wake up without an interval.
+async fn sleep_until_next_interval(interval: Option<Duration>) {
+    match until_next_interval(interval, SystemTime::now()) {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn until_next_interval(interval: Option<Duration>, now: SystemTime) -> Option<Duration> {
+    let interval = interval.filter(|interval| !interval.is_zero())?.as_secs().max(1);
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let next = (since_epoch.as_secs() / interval + 1) * interval;
+    Some(Duration::from_secs(next) - since_epoch)
+}
+
+/// number of whole intervals since the unix epoch. always 0 without an interval.
+fn interval_index(interval: Option<Duration>, time: SystemTime) -> u64 {
+    match interval {
+        Some(interval) if !interval.is_zero() => {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            since_epoch.as_secs() / interval.as_secs().max(1)
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ucs-access-log-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// names of the rotated files, oldest first
+    fn rotated_files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("access.log."))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn time(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    async fn write_at(writer: &mut Writer, now: SystemTime, line: &[u8]) {
+        writer.rotate_if_due(now, line.len() as u64).await.unwrap();
+        writer.file.write_all(line).await.unwrap();
+        writer.size += line.len() as u64;
+        writer.file.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_before_the_file_grows_over_max_size() {
+        let dir = temp_dir();
+        let path = dir.join("access.log");
+        let mut writer = Writer::open(path.clone(), Rotation { max_size: 100, ..Default::default() }).await.unwrap();
+        let line = [b'x'; 40];
+        for _ in 0..3 {
+            writer.write(&line).await.unwrap();
+        }
+        writer.file.flush().await.unwrap();
+        let rotated = rotated_files(&dir);
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(std::fs::metadata(dir.join(&rotated[0])).unwrap().len(), 80);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 40);
+        // a line over max_size gets a file of its own
+        writer.write(&[b'x'; 150]).await.unwrap();
+        writer.write(&line).await.unwrap();
+        assert_eq!(rotated_files(&dir).len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_when_the_interval_ends() {
+        let dir = temp_dir();
+        let path = dir.join("access.log");
+        let rotation = Rotation { interval: Some(Duration::from_secs(60)), ..Default::default() };
+        let mut writer = Writer::open(path.clone(), rotation).await.unwrap();
+        write_at(&mut writer, time(600), b"first\n").await;
+        write_at(&mut writer, time(659), b"second\n").await;
+        assert!(rotated_files(&dir).is_empty());
+        // without a new line, like the timer of the writer task
+        writer.rotate_if_due(time(660), 0).await.unwrap();
+        assert_eq!(rotated_files(&dir), vec!["access.log.19700101T001100Z"]);
+        assert_eq!(std::fs::read(dir.join("access.log.19700101T001100Z")).unwrap(), b"first\nsecond\n");
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        // an empty file is not rotated
+        writer.rotate_if_due(time(720), 0).await.unwrap();
+        assert_eq!(rotated_files(&dir).len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_max_files_rotated_files() {
+        let dir = temp_dir();
+        let path = dir.join("access.log");
+        let rotation = Rotation { interval: Some(Duration::from_secs(60)), max_files: 2, ..Default::default() };
+        let mut writer = Writer::open(path.clone(), rotation).await.unwrap();
+        std::fs::write(dir.join("access.log.backup"), b"not rotated").unwrap();
+        for minute in 10..15 {
+            write_at(&mut writer, time(minute * 60), b"line\n").await;
+        }
+        assert_eq!(rotated_files(&dir), vec!["access.log.19700101T001300Z", "access.log.19700101T001400Z", "access.log.backup"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn next_interval() {
+        let hour = Some(Duration::from_secs(3600));
+        assert_eq!(until_next_interval(hour, time(3600 * 5 + 10)), Some(Duration::from_secs(3590)));
+        assert_eq!(until_next_interval(hour, time(3600 * 5)), Some(Duration::from_secs(3600)));
+        assert_eq!(until_next_interval(None, time(10)), None);
+        assert_eq!(until_next_interval(Some(Duration::ZERO), time(10)), None);
+    }
+
+    #[tokio::test]
+    async fn quiet_log_rotates_on_time() {
+        let dir = temp_dir();
+        let log = AccessLog::open(dir.join("access.log"), Rotation { interval: Some(Duration::from_secs(1)), ..Default::default() }).await.unwrap();
+        log.send(b"line\n".to_vec());
+        log.flush().await;
+        for _ in 0..40 {
+            if !rotated_files(&dir).is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(rotated_files(&dir).len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_lines_when_the_writer_falls_behind() {
+        let dir = temp_dir();
+        let path = dir.join("access.log");
+        let log = AccessLog::open(&path, Rotation::default()).await.unwrap();
+        // the current thread runtime doesn't run the writer task until this test yields
+        for _ in 0..MAX_QUEUED_LINES + 10 {
+            log.send(b"line\n".to_vec());
+        }
+        assert_eq!(log.dropped(), 10);
+        log.flush().await;
+        assert_eq!(std::fs::read(&path).unwrap().len(), MAX_QUEUED_LINES * 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Admin HTTP API
+//!
+//! - `GET /connections`: the connections being served
+//! - `POST /connections/<id>/close`: cancel the open transaction of a connection and close it
+//! - `POST /cleanup?older_than=1h`: remove the temp files of failed uploads
+//! - `GET /mode`, `POST /mode?mode=read-only`: show or switch the mode
+//! - `GET /stats`: the counters of every listener
+//! - `GET /hot-keys?n=20`: the most requested keys and the keys with the most bytes transferred
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{Handler, UnityFileType};
+use crate::config::Seconds;
+use crate::connections::{ConnectionInfo, Connections};
+use crate::handlers::BoxedHandler;
+use crate::hot_keys::{HotKeyCount, HotKeys};
+use crate::http::{Request, Response};
+use crate::mode::{Mode, ModeSwitch};
+use crate::stats::Stats;
+
+/// Number of hot keys listed by default
+pub const DEFAULT_HOT_KEYS: usize = 20;
+
+/// Temp files younger than this are not removed by default, they may belong to an upload in progress
+pub const DEFAULT_CLEANUP_AGE: Duration = Duration::from_secs(60 * 60);
+
+const FILE_TYPES: [UnityFileType; UnityFileType::LENGTH] = [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource];
+
+/// A listener as seen by the admin API
+#[derive(Clone)]
+pub struct AdminListener {
+    pub name: String,
+    pub backend: String,
+    pub handler: BoxedHandler,
+    pub stats: Arc<Stats>,
+    /// None if the tracking is disabled
+    pub hot_keys: Option<Arc<HotKeys>>,
+}
+
+#[derive(Clone)]
+pub struct Admin {
+    listeners: Arc<Vec<AdminListener>>,
+    connections: Arc<Connections>,
+    mode: ModeSwitch,
+}
+
+impl Admin {
+    pub fn new(listeners: Vec<AdminListener>, connections: Arc<Connections>, mode: ModeSwitch) -> Self {
+        Self {
+            listeners: Arc::new(listeners),
+            connections,
+            mode,
+        }
+    }
+
+    pub async fn handle(&self, request: Request) -> Response {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["connections"]) => self.connections(),
+            ("POST", ["connections", id, "close"]) => self.close(id),
+            ("POST", ["cleanup"]) => self.cleanup(&request).await,
+            ("GET", ["mode"]) => mode_response(self.mode.get()),
+            ("POST", ["mode"]) => self.switch_mode(&request),
+            ("GET", ["stats"]) => self.stats(),
+            ("GET", ["hot-keys"]) => self.hot_keys(&request),
+            (_, ["connections"] | ["connections", _, "close"] | ["cleanup"] | ["mode"] | ["stats"] | ["hot-keys"]) => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        }
+    }
+
+    fn connections(&self) -> Response {
+        let connections: Vec<Value> = self.connections.list().iter().map(|connection| connection_json(connection)).collect();
+        json_response(200, json!({ "connections": connections }))
+    }
+
+    fn close(&self, id: &str) -> Response {
+        let connection = id.parse().ok().and_then(|id| self.connections.get(id));
+        match connection {
+            Some(connection) => {
+                info!(id = connection.id(), peer = connection.peer(), "close connection by admin");
+                connection.close();
+                json_response(200, json!({ "closed": connection.id() }))
+            }
+            None => json_response(404, json!({ "error": format!("no connection {}", id) })),
+        }
+    }
+
+    async fn cleanup(&self, request: &Request) -> Response {
+        let older_than = match request.query_param("older_than") {
+            None => DEFAULT_CLEANUP_AGE,
+            Some(s) => match s.parse::<Seconds>() {
+                Ok(seconds) => seconds.to_duration(),
+                Err(e) => return json_response(400, json!({ "error": e })),
+            },
+        };
+        let mut removed = serde_json::Map::new();
+        for listener in self.listeners.iter() {
+            match listener.handler.cleanup(older_than).await {
+                Ok(n) => {
+                    info!(listener = %listener.name, removed = n, ?older_than, "cleanup by admin");
+                    removed.insert(listener.name.clone(), json!(n));
+                }
+                Err(e) => {
+                    warn!(listener = %listener.name, error = %e, "cleanup error");
+                    return json_response(500, json!({ "error": format!("listener {:?}: {}", listener.name, e) }));
+                }
+            }
+        }
+        json_response(200, json!({ "removed": removed }))
+    }
+
+    fn switch_mode(&self, request: &Request) -> Response {
+        let body = String::from_utf8_lossy(&request.body);
+        let mode = match request.query_param("mode").unwrap_or(body.trim()).parse::<Mode>() {
+            Ok(mode) => mode,
+            Err(e) => return json_response(400, json!({ "error": e })),
+        };
+        self.mode.set(mode);
+        info!(%mode, "mode switched by admin");
+        mode_response(mode)
+    }
+
+    fn stats(&self) -> Response {
+        let listeners: Vec<Value> = self.listeners.iter().map(|listener| {
+            let stats = &listener.stats;
+            let by_type = |count: &dyn Fn(UnityFileType) -> u64| -> Value {
+                FILE_TYPES.iter().map(|t| (t.to_ext().to_string(), json!(count(*t)))).collect::<serde_json::Map<_, _>>().into()
+            };
+            json!({
+                "name": listener.name,
+                "backend": listener.backend,
+                "connections": {
+                    "accepted": stats.accepted(),
+                    "active": stats.active(),
+                    "rejected": stats.rejected(),
+                    "errors": stats.errors(),
+                },
+                "hits": by_type(&|t| stats.hits(t)),
+                "misses": by_type(&|t| stats.misses(t)),
+                "bytes_sent": stats.bytes_sent(),
+                "bytes_received": stats.bytes_received(),
+                "put_rejections": stats.put_rejections(),
+                "open_transactions": stats.open_transactions(),
+                "transactions_committed": stats.transactions_committed(),
+                "transactions_cancelled": stats.transactions_cancelled(),
+                "storage": stats.storage_usage().map(|usage| json!({ "files": usage.files, "bytes": usage.bytes })),
+            })
+        }).collect();
+        json_response(200, json!({
+            "mode": self.mode.get().to_string(),
+            "listeners": listeners,
+        }))
+    }
+
+    fn hot_keys(&self, request: &Request) -> Response {
+        let n = match request.query_param("n").map(|s| s.parse::<usize>()) {
+            None => DEFAULT_HOT_KEYS,
+            Some(Ok(n)) => n,
+            Some(Err(e)) => return json_response(400, json!({ "error": format!("n: {}", e) })),
+        };
+        let listeners: Vec<Value> = self.listeners.iter().filter_map(|listener| {
+            let hot_keys = listener.hot_keys.as_ref()?;
+            Some(json!({
+                "name": listener.name,
+                "window_secs": hot_keys.window().as_secs(),
+                "by_requests": hot_keys.top_requests(n).iter().map(hot_key_json).collect::<Vec<_>>(),
+                "by_bytes": hot_keys.top_bytes(n).iter().map(hot_key_json).collect::<Vec<_>>(),
+            }))
+        }).collect();
+        json_response(200, json!({ "listeners": listeners }))
+    }
+}
+
+fn hot_key_json(count: &HotKeyCount) -> Value {
+    json!({
+        "guid": count.key.guid.to_string(),
+        "hash": count.key.hash.to_string(),
+        "file_type": count.key.file_type.to_ext(),
+        "count": count.count,
+        "error": count.error,
+    })
+}
+
+fn connection_json(connection: &ConnectionInfo) -> Value {
+    let (command, command_since) = match connection.current_command() {
+        Some((kind, since)) => (Some(kind.as_str()), Some(timestamp(since))),
+        None => (None, None),
+    };
+    json!({
+        "id": connection.id(),
+        "listener": connection.listener(),
+        "peer": connection.peer(),
+        "started_at": timestamp(connection.started_at()),
+        "command": command,
+        "command_since": command_since,
+        "bytes_read": connection.bytes_read(),
+        "bytes_written": connection.bytes_written(),
+    })
+}
+
+fn mode_response(mode: Mode) -> Response {
+    json_response(200, json!({ "mode": mode.to_string() }))
+}
+
+fn json_response(status: u16, value: Value) -> Response {
+    Response::json(status, format!("{}\n", value))
+}
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{handle_with_options, HandleOptions, HexString};
+    use crate::handlers::MemoryHandler;
+    use crate::protocol::{self, Command, PROTOCOL_VERSION};
+
+    use super::*;
+
+    fn admin(handler: MemoryHandler, connections: Arc<Connections>, stats: Arc<Stats>) -> Admin {
+        let listener = AdminListener {
+            name: "default".to_string(),
+            backend: "memory".to_string(),
+            handler: BoxedHandler::new(handler),
+            stats,
+            hot_keys: None,
+        };
+        Admin::new(vec![listener], connections, ModeSwitch::default())
+    }
+
+    fn request(method: &str, target: &str) -> Request {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        Request {
+            method: method.to_string(),
+            path,
+            query,
+            body: Vec::new(),
+        }
+    }
+
+    fn body(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_methods() {
+        let admin = admin(MemoryHandler::new(), Arc::new(Connections::new()), Arc::new(Stats::new()));
+        assert_eq!(admin.handle(request("GET", "/nope")).await.status, 404);
+        assert_eq!(admin.handle(request("GET", "/connections/1")).await.status, 404);
+        assert_eq!(admin.handle(request("DELETE", "/mode")).await.status, 405);
+        assert_eq!(admin.handle(request("GET", "/connections/1/close")).await.status, 405);
+        assert_eq!(admin.handle(request("POST", "/stats")).await.status, 405);
+        assert_eq!(admin.handle(request("POST", "/connections/1/close")).await.status, 404);
+        assert_eq!(admin.handle(request("POST", "/connections/x/close")).await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn close_cancels_the_transaction_and_ends_the_session() {
+        let handler = MemoryHandler::new();
+        let connections = Arc::new(Connections::new());
+        let stats = Arc::new(Stats::new());
+        let admin = admin(handler.clone(), connections.clone(), stats.clone());
+
+        let registration = connections.register(7, "default", "10.0.0.5:1234");
+        let info = registration.info().clone();
+        let mut options = HandleOptions::new();
+        options.set_close(Some(info.close_signal()));
+        options.add_observer(stats.clone());
+        options.add_observer(info);
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let session = tokio::spawn({
+            let handler = handler.clone();
+            async move {
+                let (mut reader, mut writer) = tokio::io::split(server);
+                handle_with_options(&mut reader, &mut writer, handler, &options).await
+            }
+        });
+        protocol::Response::Version(PROTOCOL_VERSION).write_to(&mut client).await.unwrap();
+        protocol::Response::read_version_from(&mut client).await.unwrap();
+        Command::TransactionStart(HexString([1; 16]), HexString([1; 16])).write_to(&mut client).await.unwrap();
+        Command::Put(UnityFileType::Asset, 5).write_to(&mut client).await.unwrap();
+        client.write_all(b"asset").await.unwrap();
+        // the reply shows the commands before it are done
+        Command::Get(UnityFileType::Asset, HexString([2; 16]), HexString([2; 16])).write_to(&mut client).await.unwrap();
+        protocol::Response::read_from(&mut client).await.unwrap();
+        assert_eq!(stats.open_transactions(), 1);
+
+        let listed = body(&admin.handle(request("GET", "/connections")).await);
+        assert_eq!(listed["connections"][0]["id"], 7);
+        assert_eq!(listed["connections"][0]["peer"], "10.0.0.5:1234");
+
+        let response = admin.handle(request("POST", "/connections/7/close")).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response)["closed"], 7);
+        session.await.unwrap().unwrap();
+        assert_eq!(stats.open_transactions(), 0);
+        assert_eq!(handler.file_count().await, 0);
+    }
+}
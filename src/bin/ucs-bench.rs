@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::io;
+
+use unity_cache_server::{UnityFileGuid, UnityFileHash, UnityFileType};
+use unity_cache_server::client::CacheClient;
+use unity_cache_server::config::ByteSize;
+use unity_cache_server::protocol::{Command, read_version};
+use unity_cache_server::recorder::Recording;
+
+/// Replay recorded sessions or generate synthetic load against a Unity cache server
+#[derive(Debug, Parser)]
+#[command(name = "ucs-bench")]
+struct Cli {
+    /// Server address
+    #[arg(short, long, default_value = "127.0.0.1:8126")]
+    server: String,
+
+    /// Number of concurrent connections
+    #[arg(short, long, default_value_t = 8)]
+    concurrency: usize,
+
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Debug, Subcommand)]
+enum Mode {
+    /// Replay recorded sessions. Each session runs on its own connection.
+    Replay {
+        /// Session recording files
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Replay every session this many times
+        #[arg(long, default_value_t = 1)]
+        repeat: usize,
+        /// Keep the recorded delays between commands
+        #[arg(long)]
+        realtime: bool,
+    },
+    /// Generate a synthetic mix of gets and puts
+    Synthetic {
+        /// Total number of requests
+        #[arg(long, default_value_t = 10000)]
+        requests: u64,
+        /// Fraction of requests that are gets. The rest are put transactions.
+        #[arg(long, default_value_t = 0.9)]
+        get_ratio: f64,
+        /// Fraction of gets that ask for a seeded key
+        #[arg(long, default_value_t = 0.8)]
+        hit_ratio: f64,
+        /// Number of keys seeded before the run
+        #[arg(long, default_value_t = 1000)]
+        keys: usize,
+        /// Min file size, e.g. 512, 64k, 1m. Sizes are log-uniformly distributed.
+        #[arg(long, default_value = "1k")]
+        min_size: ByteSize,
+        /// Max file size
+        #[arg(long, default_value = "1m")]
+        max_size: ByteSize,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Get(UnityFileType, UnityFileGuid, UnityFileHash),
+    Put {
+        guid: UnityFileGuid,
+        hash: UnityFileHash,
+        files: Vec<(UnityFileType, Bytes)>,
+    },
+}
+
+/// ops of a session with their offsets from the session start
+type Session = Vec<(Duration, Op)>;
+
+async fn load_session(path: &PathBuf) -> anyhow::Result<Session> {
+    let recording = Recording::open(path).await
+        .with_context(|| format!("open {} failed", path.display()))?;
+    if recording.truncated {
+        println!("{}: truncated recording. replay stops where it ends", path.display());
+    }
+    let bytes = recording.bytes();
+    // start position of every chunk in the byte stream
+    let mut chunk_starts = Vec::with_capacity(recording.chunks.len());
+    let mut position = 0u64;
+    for chunk in &recording.chunks {
+        chunk_starts.push((position, chunk.offset));
+        position += chunk.data.len() as u64;
+    }
+    let offset_at = |position: u64| {
+        let i = chunk_starts.partition_point(|(start, _)| *start <= position);
+        chunk_starts.get(i.saturating_sub(1)).map(|(_, offset)| *offset).unwrap_or_default()
+    };
+
+    let mut cursor = Cursor::new(&bytes[..]);
+    read_version(&mut cursor).await
+        .with_context(|| format!("{} has no version handshake", path.display()))?;
+    let mut session = Vec::new();
+    let mut transaction = None;
+    loop {
+        let offset = offset_at(cursor.position());
+        let command = match Command::read_from(&mut cursor).await {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(e) => {
+                println!("{}: stop at byte {}: {}", path.display(), cursor.position(), e);
+                break;
+            }
+        };
+        match command {
+            Command::Get(t, guid, hash) => session.push((offset, Op::Get(t, guid, hash))),
+            Command::TransactionStart(guid, hash) => transaction = Some((offset, guid, hash, Vec::new())),
+            Command::TransactionEnd => {
+                if let Some((offset, guid, hash, files)) = transaction.take() {
+                    session.push((offset, Op::Put { guid, hash, files }));
+                }
+            }
+            Command::Put(t, size) => {
+                let start = cursor.position() as usize;
+                let end = start.saturating_add(size as usize);
+                if end > bytes.len() {
+                    println!("{}: stop at byte {}: truncated payload", path.display(), start);
+                    break;
+                }
+                cursor.set_position(end as u64);
+                if let Some((_, _, _, files)) = &mut transaction {
+                    files.push((t, Bytes::copy_from_slice(&bytes[start..end])));
+                }
+            }
+            Command::Quit => break,
+        }
+    }
+    Ok(session)
+}
+
+#[derive(Debug, Default)]
+struct Samples(BTreeMap<&'static str, (Vec<Duration>, u64)>);
+
+impl Samples {
+    fn add(&mut self, kind: &'static str, latency: Duration, bytes: u64) {
+        let (latencies, total_bytes) = self.0.entry(kind).or_default();
+        latencies.push(latency);
+        *total_bytes += bytes;
+    }
+
+    fn merge(&mut self, other: Samples) {
+        for (kind, (latencies, bytes)) in other.0 {
+            let (all, total_bytes) = self.0.entry(kind).or_default();
+            all.extend(latencies);
+            *total_bytes += bytes;
+        }
+    }
+
+    fn report(mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        println!("elapsed {:.3}s", seconds);
+        println!("{:<10} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}", "command", "count", "ops/s", "MiB/s", "p50 ms", "p90 ms", "p99 ms", "max ms");
+        for (kind, (latencies, bytes)) in self.0.iter_mut() {
+            latencies.sort();
+            let percentile = |p: f64| {
+                let i = ((latencies.len() - 1) as f64 * p).round() as usize;
+                latencies[i].as_secs_f64() * 1000.0
+            };
+            println!("{:<10} {:>8} {:>10.1} {:>10.2} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+                     kind,
+                     latencies.len(),
+                     latencies.len() as f64 / seconds,
+                     *bytes as f64 / seconds / (1024.0 * 1024.0),
+                     percentile(0.5),
+                     percentile(0.9),
+                     percentile(0.99),
+                     percentile(1.0));
+        }
+    }
+}
+
+async fn run_op(client: &mut CacheClient<tokio::net::TcpStream>, op: &Op, samples: &mut Samples) -> anyhow::Result<()> {
+    let start = Instant::now();
+    match op {
+        Op::Get(t, guid, hash) => match client.get(*t, guid, hash).await? {
+            None => samples.add("get-miss", start.elapsed(), 0),
+            Some((size, mut reader)) => {
+                let n = io::copy(&mut reader, &mut io::sink()).await?;
+                if n != size {
+                    anyhow::bail!("incomplete file: {} of {} bytes", n, size);
+                }
+                samples.add("get-hit", start.elapsed(), size);
+            }
+        },
+        Op::Put { guid, hash, files } => {
+            let bytes = files.iter().map(|(_, data)| data.len() as u64).sum();
+            let last = files.last().map(|(t, _)| *t);
+            let files = files.iter().map(|(t, data)| (*t, data.len() as u64, &data[..])).collect();
+            client.put_transaction(*guid, *hash, files).await?;
+            // puts are not acknowledged, but the server commits a transaction before it reads the next command.
+            // so the reply to a get of the last file tells when the put is stored.
+            let reply = match last {
+                Some(t) => client.get(t, guid, hash).await?,
+                None => None,
+            };
+            samples.add("put", start.elapsed(), bytes);
+            if let Some((_, mut reader)) = reply {
+                io::copy(&mut reader, &mut io::sink()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn replay(server: String, concurrency: usize, sessions: Vec<Session>, realtime: bool) -> anyhow::Result<Samples> {
+    let queue = Arc::new(Mutex::new(sessions.into_iter().map(Arc::new).collect::<VecDeque<_>>()));
+    let mut workers = Vec::new();
+    for _ in 0..concurrency {
+        let queue = queue.clone();
+        let server = server.clone();
+        workers.push(tokio::spawn(async move {
+            let mut samples = Samples::default();
+            loop {
+                let session = match queue.lock().unwrap().pop_front() {
+                    None => break,
+                    Some(session) => session,
+                };
+                let mut client = CacheClient::connect(&server).await?;
+                let started = Instant::now();
+                for (offset, op) in session.iter() {
+                    if realtime {
+                        tokio::time::sleep_until((started + *offset).into()).await;
+                    }
+                    run_op(&mut client, op, &mut samples).await?;
+                }
+                client.quit().await?;
+            }
+            anyhow::Ok(samples)
+        }));
+    }
+    let mut samples = Samples::default();
+    for worker in workers {
+        samples.merge(worker.await??);
+    }
+    Ok(samples)
+}
+
+fn random_key(rng: &mut StdRng) -> (UnityFileGuid, UnityFileHash) {
+    let mut guid = UnityFileGuid::new();
+    let mut hash = UnityFileHash::new();
+    rng.fill_bytes(&mut guid.0);
+    rng.fill_bytes(&mut hash.0);
+    (guid, hash)
+}
+
+fn random_put(rng: &mut StdRng, guid: UnityFileGuid, hash: UnityFileHash, min_size: u64, max_size: u64) -> Op {
+    let file = |rng: &mut StdRng| {
+        let (min, max) = ((min_size.max(1) as f64).ln(), (max_size.max(min_size).max(1) as f64).ln());
+        let size = rng.gen_range(min..=max).exp() as usize;
+        let mut data = vec![0u8; size];
+        rng.fill_bytes(&mut data);
+        Bytes::from(data)
+    };
+    let files = vec![(UnityFileType::Asset, file(rng)), (UnityFileType::Info, file(rng))];
+    Op::Put { guid, hash, files }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn synthetic(server: String, concurrency: usize, requests: u64, get_ratio: f64, hit_ratio: f64, keys: usize, min_size: u64, max_size: u64) -> anyhow::Result<(Samples, Duration)> {
+    let mut rng = StdRng::from_entropy();
+    let seeded: Vec<_> = (0..keys).map(|_| random_key(&mut rng)).collect();
+    println!("seeding {} keys", seeded.len());
+    let mut client = CacheClient::connect(&server).await?;
+    for (guid, hash) in seeded.iter() {
+        let op = random_put(&mut rng, *guid, *hash, min_size, max_size);
+        run_op(&mut client, &op, &mut Samples::default()).await?;
+    }
+    client.quit().await?;
+
+    let seeded = Arc::new(seeded);
+    let remaining = Arc::new(AtomicU64::new(requests));
+    let started = Instant::now();
+    let mut workers = Vec::new();
+    for _ in 0..concurrency {
+        let server = server.clone();
+        let seeded = seeded.clone();
+        let remaining = remaining.clone();
+        workers.push(tokio::spawn(async move {
+            let mut rng = StdRng::from_entropy();
+            let mut samples = Samples::default();
+            let mut client = CacheClient::connect(&server).await?;
+            while remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                let op = if rng.gen_bool(get_ratio.clamp(0.0, 1.0)) {
+                    let t = if rng.gen_bool(0.5) { UnityFileType::Asset } else { UnityFileType::Info };
+                    let (guid, hash) = if !seeded.is_empty() && rng.gen_bool(hit_ratio.clamp(0.0, 1.0)) {
+                        seeded[rng.gen_range(0..seeded.len())]
+                    } else {
+                        random_key(&mut rng)
+                    };
+                    Op::Get(t, guid, hash)
+                } else {
+                    let (guid, hash) = random_key(&mut rng);
+                    random_put(&mut rng, guid, hash, min_size, max_size)
+                };
+                run_op(&mut client, &op, &mut samples).await?;
+            }
+            client.quit().await?;
+            anyhow::Ok(samples)
+        }));
+    }
+    let mut samples = Samples::default();
+    for worker in workers {
+        samples.merge(worker.await??);
+    }
+    Ok((samples, started.elapsed()))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let concurrency = cli.concurrency.max(1);
+    match cli.mode {
+        Mode::Replay { files, repeat, realtime } => {
+            let mut sessions = Vec::new();
+            for path in files.iter() {
+                let session = load_session(path).await?;
+                println!("{}: {} ops", path.display(), session.len());
+                sessions.push(session);
+            }
+            let sessions: Vec<_> = (0..repeat).flat_map(|_| sessions.iter().cloned()).collect();
+            let started = Instant::now();
+            let samples = replay(cli.server, concurrency, sessions, realtime).await?;
+            samples.report(started.elapsed());
+        }
+        Mode::Synthetic { requests, get_ratio, hit_ratio, keys, min_size, max_size } => {
+            let (samples, elapsed) = synthetic(cli.server, concurrency, requests, get_ratio, hit_ratio, keys, min_size.0, max_size.0).await?;
+            samples.report(elapsed);
+        }
+    }
+    Ok(())
+}
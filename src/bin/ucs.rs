@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+
+use unity_cache_server::{HexString, UnityFileGuid, UnityFileHash, UnityFileType};
+use unity_cache_server::client::CacheClient;
+use unity_cache_server::report::Report;
+
+/// Manual operations on a running Unity cache server
+#[derive(Debug, Parser)]
+#[command(name = "ucs")]
+struct Cli {
+    /// Server address
+    #[arg(short, long, default_value = "127.0.0.1:8126")]
+    server: String,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    #[command(flatten)]
+    Server(ServerCommands),
+    /// Report hit rates, cold misses, unread puts and bytes by type from access logs or session recordings.
+    /// It works offline and does not connect to the server.
+    Report {
+        /// Access log or session recording files, in any order
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Number of keys to list for cold misses and unread puts
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+}
+
+/// Commands sent to the server
+#[derive(Debug, Subcommand)]
+enum ServerCommands {
+    /// Check the version reply of the server
+    Handshake,
+    /// Download a file
+    Get {
+        /// File type: a, i, r or bin, info, resource
+        #[arg(value_parser = parse_file_type)]
+        r#type: UnityFileType,
+        #[arg(value_parser = parse_hex_string::<16>)]
+        guid: UnityFileGuid,
+        #[arg(value_parser = parse_hex_string::<16>)]
+        hash: UnityFileHash,
+        /// Output file
+        output: PathBuf,
+    },
+    /// Upload files of a guid/hash in one transaction
+    Put {
+        #[arg(value_parser = parse_hex_string::<16>)]
+        guid: UnityFileGuid,
+        #[arg(value_parser = parse_hex_string::<16>)]
+        hash: UnityFileHash,
+        /// Asset file (.bin)
+        #[arg(long)]
+        asset: Option<PathBuf>,
+        /// Info file (.info)
+        #[arg(long)]
+        info: Option<PathBuf>,
+        /// Resource file (.resource)
+        #[arg(long)]
+        resource: Option<PathBuf>,
+    },
+    /// Check whether files of a guid/hash exist
+    Probe {
+        #[arg(value_parser = parse_hex_string::<16>)]
+        guid: UnityFileGuid,
+        #[arg(value_parser = parse_hex_string::<16>)]
+        hash: UnityFileHash,
+        /// Only probe this file type. All types by default.
+        #[arg(short, long, value_parser = parse_file_type)]
+        r#type: Option<UnityFileType>,
+    },
+}
+
+fn parse_file_type(s: &str) -> Result<UnityFileType, String> {
+    let t = match s.as_bytes() {
+        [b] => UnityFileType::try_from_ext_char(*b),
+        _ => UnityFileType::try_from_ext(s),
+    };
+    t.map_err(|_| format!("unknown file type {:?}", s))
+}
+
+fn parse_hex_string<const N: usize>(s: &str) -> Result<HexString<N>, String> {
+    HexString::from_hex_string(s.to_string()).map_err(|e| e.to_string())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Commands::Report { files, top } => {
+            let mut report = Report::new();
+            for path in files {
+                report.add_file(path).await?;
+            }
+            print!("{}", report.render(top));
+            Ok(())
+        }
+        Commands::Server(command) => {
+            let mut client = CacheClient::connect(&cli.server).await
+                .with_context(|| format!("connect to {} failed", cli.server))?;
+            serve_command(&mut client, command).await?;
+            client.quit().await?;
+            Ok(())
+        }
+    }
+}
+
+async fn serve_command(client: &mut CacheClient<TcpStream>, command: ServerCommands) -> anyhow::Result<()> {
+    match command {
+        ServerCommands::Handshake => {
+            println!("version {}", client.version());
+        }
+        ServerCommands::Get { r#type, guid, hash, output } => {
+            match client.get(r#type, &guid, &hash).await? {
+                None => {
+                    anyhow::bail!("miss {} {} {}", r#type.to_ext(), guid, hash);
+                }
+                Some((size, mut reader)) => {
+                    let mut file = BufWriter::new(File::create(&output).await?);
+                    let n = tokio::io::copy(&mut reader, &mut file).await?;
+                    file.flush().await?;
+                    if n != size {
+                        anyhow::bail!("incomplete file: {} of {} bytes", n, size);
+                    }
+                    println!("hit {} {} {} {} bytes -> {}", r#type.to_ext(), guid, hash, size, output.display());
+                }
+            }
+        }
+        ServerCommands::Put { guid, hash, asset, info, resource } => {
+            let mut files = Vec::new();
+            for (t, path) in [(UnityFileType::Asset, asset), (UnityFileType::Info, info), (UnityFileType::Resource, resource)] {
+                if let Some(path) = path {
+                    let file = File::open(&path).await
+                        .with_context(|| format!("open {} failed", path.display()))?;
+                    let size = file.metadata().await?.len();
+                    files.push((t, size, BufReader::new(file)));
+                }
+            }
+            if files.is_empty() {
+                anyhow::bail!("no file to put. use --asset, --info or --resource");
+            }
+            let count = files.len();
+            client.put_transaction(guid, hash, files).await?;
+            println!("put {} {} {} files", guid, hash, count);
+        }
+        ServerCommands::Probe { guid, hash, r#type } => {
+            let types = match r#type {
+                Some(t) => vec![t],
+                None => vec![UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource],
+            };
+            for t in types {
+                match client.probe(t, &guid, &hash).await? {
+                    None => println!("miss {} {} {}", t.to_ext(), guid, hash),
+                    Some(size) => println!("hit {} {} {} {} bytes", t.to_ext(), guid, hash, size),
+                }
+            }
+        }
+    }
+    Ok(())
+}
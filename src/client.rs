@@ -0,0 +1,205 @@
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream, Take};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{Error, Result, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::protocol::{Command, PROTOCOL_VERSION, Response};
+
+#[derive(Debug)]
+pub struct CacheClient<S> {
+    stream: BufStream<S>,
+    version: u32,
+}
+
+impl CacheClient<TcpStream> {
+    /// connect to a server and do the version handshake
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Self::new(stream).await
+    }
+}
+
+impl<S> CacheClient<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+{
+    /// do the version handshake on a connected stream
+    pub async fn new(stream: S) -> Result<Self> {
+        let mut stream = BufStream::new(stream);
+        Response::Version(PROTOCOL_VERSION).write_to(&mut stream).await?;
+        stream.flush().await?;
+        let version = match Response::read_version_from(&mut stream).await? {
+            Response::Version(version) => version,
+            _ => return Err(Error::ResponseNotMatched),
+        };
+        if version != PROTOCOL_VERSION {
+            return Err(Error::WrongVersion(version));
+        }
+        Ok(Self {
+            stream,
+            version,
+        })
+    }
+
+    /// version replied by the server
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// get a file from server
+    /// the returned reader must be read to the end before sending the next command
+    pub async fn get(&mut self, t: UnityFileType, guid: &UnityFileGuid, hash: &UnityFileHash) -> Result<Option<(u64, Take<&mut BufStream<S>>)>> {
+        Command::Get(t, *guid, *hash).write_to(&mut self.stream).await?;
+        self.stream.flush().await?;
+        match Response::read_from(&mut self.stream).await? {
+            Response::Hit(rt, size, rguid, rhash) if (rt, &rguid, &rhash) == (t, guid, hash) => {
+                Ok(Some((size, (&mut self.stream).take(size))))
+            }
+            Response::Miss(rt, rguid, rhash) if (rt, &rguid, &rhash) == (t, guid, hash) => Ok(None),
+            _ => Err(Error::ResponseNotMatched),
+        }
+    }
+
+    /// check whether a file exists. the file content is discarded.
+    pub async fn probe(&mut self, t: UnityFileType, guid: &UnityFileGuid, hash: &UnityFileHash) -> Result<Option<u64>> {
+        match self.get(t, guid, hash).await? {
+            None => Ok(None),
+            Some((size, mut reader)) => {
+                let n = io::copy(&mut reader, &mut io::sink()).await?;
+                if n != size {
+                    return Err(Error::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+                }
+                Ok(Some(size))
+            }
+        }
+    }
+
+    /// put files in one transaction
+    /// each reader must provide exactly `size` bytes
+    pub async fn put_transaction<R>(&mut self, guid: UnityFileGuid, hash: UnityFileHash, files: Vec<(UnityFileType, u64, R)>) -> Result<()>
+        where
+            R: AsyncRead + Unpin
+    {
+        Command::TransactionStart(guid, hash).write_to(&mut self.stream).await?;
+        for (t, size, reader) in files {
+            Command::Put(t, size).write_to(&mut self.stream).await?;
+            let n = io::copy(&mut reader.take(size), &mut self.stream).await?;
+            if n != size {
+                return Err(Error::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+            }
+        }
+        Command::TransactionEnd.write_to(&mut self.stream).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// end the session
+    pub async fn quit(mut self) -> Result<()> {
+        Command::Quit.write_to(&mut self.stream).await?;
+        self.stream.flush().await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    use crate::handle;
+    use crate::handlers::MemoryHandler;
+    use crate::HexString;
+
+    use super::*;
+
+    fn key(n: u8) -> (UnityFileGuid, UnityFileHash) {
+        (HexString([n; 16]), HexString([n; 16]))
+    }
+
+    /// a client of a session served by `handler`
+    async fn connect(handler: MemoryHandler) -> (CacheClient<DuplexStream>, JoinHandle<Result<()>>) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let session = tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(server);
+            handle(&mut reader, &mut writer, handler).await
+        });
+        (CacheClient::new(client).await.unwrap(), session)
+    }
+
+    /// a server which replies `reply` to the version and to the first command, whatever the client sends
+    fn fake_server(server: DuplexStream, version: u32, reply: Option<Response>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut server = BufStream::new(server);
+            let mut buf = [0u8; 8];
+            server.read_exact(&mut buf).await.unwrap();
+            Response::Version(version).write_to(&mut server).await.unwrap();
+            server.flush().await.unwrap();
+            if let Some(reply) = reply {
+                Command::read_from(&mut server).await.unwrap();
+                reply.write_to(&mut server).await.unwrap();
+                server.flush().await.unwrap();
+            }
+        })
+    }
+
+    async fn read_file(reader: &mut (impl AsyncRead + Unpin)) -> Vec<u8> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await.unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = fake_server(server, 0xfd, None);
+        assert!(matches!(CacheClient::new(client).await, Err(Error::WrongVersion(0xfd))));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn put_transaction_then_get_hit_and_miss() {
+        let (mut client, session) = connect(MemoryHandler::new()).await;
+        assert_eq!(client.version(), PROTOCOL_VERSION);
+        let (guid, hash) = key(1);
+        client.put_transaction(guid, hash, vec![(UnityFileType::Asset, 5, &b"asset"[..]), (UnityFileType::Info, 4, &b"info"[..])]).await.unwrap();
+
+        let (size, mut reader) = client.get(UnityFileType::Asset, &guid, &hash).await.unwrap().unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(read_file(&mut reader).await, b"asset");
+        let (size, mut reader) = client.get(UnityFileType::Info, &guid, &hash).await.unwrap().unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(read_file(&mut reader).await, b"info");
+        assert!(client.get(UnityFileType::Resource, &guid, &hash).await.unwrap().is_none());
+        assert!(client.get(UnityFileType::Asset, &key(2).0, &key(2).1).await.unwrap().is_none());
+
+        client.quit().await.unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn probe_drains_the_file() {
+        let (mut client, session) = connect(MemoryHandler::new()).await;
+        let (guid, hash) = key(1);
+        client.put_transaction(guid, hash, vec![(UnityFileType::Resource, 3, &b"abc"[..])]).await.unwrap();
+        assert_eq!(client.probe(UnityFileType::Resource, &guid, &hash).await.unwrap(), Some(3));
+        assert_eq!(client.probe(UnityFileType::Asset, &guid, &hash).await.unwrap(), None);
+        // the stream is still in sync after the drained file
+        let (_, mut reader) = client.get(UnityFileType::Resource, &guid, &hash).await.unwrap().unwrap();
+        assert_eq!(read_file(&mut reader).await, b"abc");
+        client.quit().await.unwrap();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reply_for_another_file_is_not_matched() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (guid, hash) = key(2);
+        let server = fake_server(server, PROTOCOL_VERSION, Some(Response::Miss(UnityFileType::Asset, guid, hash)));
+        let mut client = CacheClient::new(client).await.unwrap();
+        let (guid, hash) = key(1);
+        assert!(matches!(client.get(UnityFileType::Asset, &guid, &hash).await, Err(Error::ResponseNotMatched)));
+        server.await.unwrap();
+    }
+}
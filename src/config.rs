@@ -0,0 +1,1191 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+
+use crate::HandleOptions;
+use crate::access_log::Rotation;
+use crate::handlers::{BoxedHandler, FileSystemHandler, MemoryHandler, NopHandler};
+use crate::limits::{ConnectionLimiter, LimitPolicy};
+use crate::listener::ListenAddr;
+use crate::mode::Mode;
+
+/// Command line arguments of the server. They override the config file.
+#[derive(Debug, Default, Parser)]
+#[command(name = "unity-cache-server", version, about = "A Unity cache server")]
+pub struct Args {
+    /// TOML config file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on: ip:port, unix:PATH, systemd or systemd:NAME. Repeat for several addresses. Not allowed with `[[listener]]` in the config file.
+    #[arg(short, long)]
+    pub listen: Vec<String>,
+
+    /// Expect a PROXY protocol v1 or v2 header from a load balancer on every connection
+    #[arg(long)]
+    pub proxy_protocol: bool,
+
+    /// Ip ranges of the load balancers whose PROXY protocol header is trusted, e.g. 10.0.0.10/32.
+    /// Repeat for several ranges. Required with --proxy-protocol.
+    #[arg(long)]
+    pub trusted_proxy: Vec<IpNet>,
+
+    /// Only clients in these ip ranges may upload, e.g. 10.0.0.0/8. Repeat for several ranges.
+    /// Everyone may upload if not set.
+    #[arg(long)]
+    pub put_allowlist: Vec<IpNet>,
+
+    /// Mode at startup: normal, read-only or pass-through-miss. SIGUSR2 switches to the next mode.
+    #[arg(long)]
+    pub mode: Option<Mode>,
+
+    /// Storage backend
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+
+    /// Directory of the cached files
+    #[arg(long)]
+    pub base_path: Option<PathBuf>,
+
+    /// Directory of the files being uploaded. Should be on the same file system as the base path.
+    #[arg(long)]
+    pub temp_path: Option<PathBuf>,
+
+    /// Max size of an uploaded file, e.g. 256MiB. 0 for no limit.
+    #[arg(long)]
+    pub max_file_size: Option<ByteSize>,
+
+    /// Max number of get lookups running at the same time for one connection
+    #[arg(long)]
+    pub pipeline_depth: Option<usize>,
+
+    /// Directory to record the inbound byte stream of every connection into
+    #[arg(long, env = "UNITY_CACHE_SERVER_RECORD_DIR")]
+    pub record_dir: Option<PathBuf>,
+
+    /// Time for open transactions to finish after a shutdown signal, e.g. 30s
+    #[arg(long)]
+    pub shutdown_grace_period: Option<Seconds>,
+
+    /// Max time to receive the version from a new client. 0 for no limit.
+    #[arg(long)]
+    pub handshake_timeout: Option<Seconds>,
+
+    /// Max time to wait for the next command. 0 for no limit.
+    #[arg(long)]
+    pub idle_timeout: Option<Seconds>,
+
+    /// Max time from transaction start to transaction end. 0 for no limit.
+    #[arg(long)]
+    pub transaction_timeout: Option<Seconds>,
+
+    /// Min speed of a file transfer per second, e.g. 16KiB. 0 for no limit.
+    #[arg(long)]
+    pub min_transfer_rate: Option<ByteSize>,
+
+    /// Max number of concurrent connections. 0 for no limit.
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// Max number of concurrent connections from one client ip. 0 for no limit.
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// What to do with a connection over the limits
+    #[arg(long, value_enum)]
+    pub limit_policy: Option<LimitPolicyConfig>,
+
+    /// Log level. SIGHUP reloads it from the config file.
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+
+    /// Log format
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Write a JSON lines access log with one record per command to this file
+    #[arg(long)]
+    pub access_log: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9126
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Address to serve the admin API on, e.g. 127.0.0.1:9127. It has no authentication.
+    #[arg(long)]
+    pub admin_listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub timeouts: TimeoutsConfig,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+    pub access_log: AccessLogConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub hot_keys: HotKeysConfig,
+    /// Listeners with their own storage and limits.
+    /// If there is none, `server.listen` is served with the top-level `storage` and `limits`.
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<String>,
+    pub pipeline_depth: usize,
+    pub record_dir: Option<PathBuf>,
+    pub shutdown_grace_period: Seconds,
+    /// Expect a PROXY protocol header on every connection
+    pub proxy_protocol: bool,
+    /// Only connections from these ip ranges may send a PROXY protocol header
+    pub trusted_proxies: Vec<IpNet>,
+    /// Only clients in these ip ranges may upload. Everyone may upload if empty.
+    pub put_allowlist: Vec<IpNet>,
+    /// Mode at startup
+    pub mode: Mode,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec!["0.0.0.0:8126".to_string()],
+            pipeline_depth: HandleOptions::DEFAULT_PIPELINE_DEPTH,
+            record_dir: None,
+            shutdown_grace_period: Seconds(30),
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            put_allowlist: Vec::new(),
+            mode: Mode::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// files on disk
+    Fs,
+    /// files in memory, lost on exit
+    Memory,
+    /// cache nothing
+    Nop,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Fs => write!(f, "fs"),
+            Backend::Memory => write!(f, "memory"),
+            Backend::Nop => write!(f, "nop"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    pub base_path: PathBuf,
+    pub temp_path: PathBuf,
+    pub max_file_size: ByteSize,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Fs,
+            base_path: PathBuf::from(".cache_fs"),
+            temp_path: PathBuf::from(".cache_fs"),
+            max_file_size: ByteSize(256 * 1024 * 1024),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub handshake: Seconds,
+    pub idle: Seconds,
+    pub transaction: Seconds,
+    pub min_transfer_rate: ByteSize,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            handshake: Seconds(30),
+            idle: Seconds(60 * 60),
+            transaction: Seconds(10 * 60),
+            min_transfer_rate: ByteSize(16 * 1024),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitPolicyConfig {
+    Queue,
+    Reject,
+}
+
+impl From<LimitPolicyConfig> for LimitPolicy {
+    fn from(policy: LimitPolicyConfig) -> Self {
+        match policy {
+            LimitPolicyConfig::Queue => LimitPolicy::Queue,
+            LimitPolicyConfig::Reject => LimitPolicy::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub policy: LimitPolicyConfig,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_connections_per_ip: 0,
+            policy: LimitPolicyConfig::Queue,
+        }
+    }
+}
+
+impl StorageConfig {
+    /// create the handler of the configured backend.
+    /// `max_file_size` is checked by [`Config::handle_options`] instead, so a reload can change it.
+    pub fn create_handler(&self) -> BoxedHandler {
+        match self.backend {
+            Backend::Fs => BoxedHandler::new(FileSystemHandler::new(self.base_path.clone(), self.temp_path.clone())),
+            Backend::Memory => BoxedHandler::new(MemoryHandler::new()),
+            Backend::Nop => BoxedHandler::new(NopHandler::new()),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn connection_limiter(&self) -> ConnectionLimiter {
+        ConnectionLimiter::new(self.max_connections, self.max_connections_per_ip, self.policy.into())
+    }
+}
+
+/// A set of addresses served by one cache namespace
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Name used in logs and stats
+    pub name: String,
+    pub listen: Vec<String>,
+    /// The top-level `storage` if not set
+    pub storage: Option<StorageConfig>,
+    /// The top-level `limits` if not set
+    pub limits: Option<LimitsConfig>,
+    /// `server.proxy_protocol` if not set
+    pub proxy_protocol: Option<bool>,
+    /// `server.trusted_proxies` if not set
+    pub trusted_proxies: Option<Vec<IpNet>>,
+    /// `server.put_allowlist` if not set
+    pub put_allowlist: Option<Vec<IpNet>>,
+}
+
+/// A listener with the top-level settings filled in
+#[derive(Debug, Clone)]
+pub struct ListenerSettings {
+    pub name: String,
+    pub listen: Vec<String>,
+    pub storage: StorageConfig,
+    pub limits: LimitsConfig,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub put_allowlist: Vec<IpNet>,
+}
+
+impl ListenerSettings {
+    /// take the settings which can change without a restart
+    pub fn reload(&mut self, new: &ListenerSettings) {
+        self.storage.max_file_size = new.storage.max_file_size;
+        self.limits = new.limits.clone();
+        self.trusted_proxies = new.trusted_proxies.clone();
+        self.put_allowlist = new.put_allowlist.clone();
+    }
+
+    pub fn listen_addrs(&self) -> Vec<ListenAddr> {
+        // validated in `Config::validate`
+        self.listen.iter().filter_map(|addr| addr.parse().ok()).collect()
+    }
+
+    /// whether a client may upload. clients without an ip, e.g. on a unix socket, only if there is no allowlist.
+    pub fn is_put_allowed(&self, ip: Option<IpAddr>) -> bool {
+        self.put_allowlist.is_empty() || contains(&self.put_allowlist, ip)
+    }
+
+    /// whether the PROXY protocol header of a peer is read.
+    /// peers without an ip, e.g. on a unix socket, are never trusted.
+    pub fn is_trusted_proxy(&self, ip: Option<IpAddr>) -> bool {
+        self.proxy_protocol && contains(&self.trusted_proxies, ip)
+    }
+}
+
+fn contains(nets: &[IpNet], ip: Option<IpAddr>) -> bool {
+    match ip {
+        Some(ip) => {
+            // an ipv4 client on an ipv6 socket shows as ::ffff:a.b.c.d
+            let ip = match ip {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+                ip => ip,
+            };
+            nets.iter().any(|net| net.contains(&ip))
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// also log every command
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable lines
+    Text,
+    /// one json object per line
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Log a summary of the stats of every listener this often. 0 for never. SIGUSR1 logs it at any time.
+    pub stats_interval: Seconds,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            format: LogFormat::Text,
+            stats_interval: Seconds(0),
+        }
+    }
+}
+
+/// JSON lines log of every command. Disabled without a path.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub path: Option<PathBuf>,
+    /// Rotate before the file grows over this size. 0 for no limit.
+    pub max_size: ByteSize,
+    /// Rotate every interval, e.g. 1d starts a new file at 00:00 UTC. 0 for never.
+    pub rotate_interval: Seconds,
+    /// Number of rotated files to keep. 0 keeps all of them.
+    pub max_files: usize,
+}
+
+/// Prometheus metrics at `http://<listen>/metrics`. Disabled without an address.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: Option<SocketAddr>,
+    /// How often to measure the storage usage. It walks the directories of the fs backend.
+    pub storage_usage_interval: Seconds,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            storage_usage_interval: Seconds(60),
+        }
+    }
+}
+
+/// Admin HTTP API. Disabled without an address. It has no authentication, so keep it on a private address.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub listen: Option<SocketAddr>,
+}
+
+/// Approximate top-N of the most requested keys and of the keys with the most bytes transferred
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotKeysConfig {
+    /// Keys counted per listener and sixth of the window. More is more accurate. 0 disables the tracking.
+    pub capacity: usize,
+    /// The counts cover this much of the recent past
+    pub window: Seconds,
+    /// Number of keys in the log summary
+    pub top: usize,
+    /// Log the top keys of every listener this often. 0 for never.
+    pub log_interval: Seconds,
+}
+
+impl Default for HotKeysConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            window: Seconds(60 * 60),
+            top: 10,
+            log_interval: Seconds(0),
+        }
+    }
+}
+
+impl AccessLogConfig {
+    pub fn rotation(&self) -> Rotation {
+        Rotation {
+            max_size: self.max_size.0,
+            interval: self.rotate_interval.to_option(),
+            max_files: self.max_files,
+        }
+    }
+}
+
+impl Config {
+    /// read the config file given in the arguments, if any, then apply the other arguments
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        if !args.listen.is_empty() && !config.listeners.is_empty() {
+            bail!("--listen can't be used when the config file has [[listener]] entries");
+        }
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("read config file {} failed", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if !args.listen.is_empty() {
+            self.server.listen = args.listen.clone();
+        }
+        if let Some(v) = args.pipeline_depth {
+            self.server.pipeline_depth = v;
+        }
+        if let Some(v) = &args.record_dir {
+            self.server.record_dir = Some(v.clone());
+        }
+        if let Some(v) = args.shutdown_grace_period {
+            self.server.shutdown_grace_period = v;
+        }
+        if args.proxy_protocol {
+            self.server.proxy_protocol = true;
+        }
+        if !args.trusted_proxy.is_empty() {
+            self.server.trusted_proxies = args.trusted_proxy.clone();
+        }
+        if !args.put_allowlist.is_empty() {
+            self.server.put_allowlist = args.put_allowlist.clone();
+        }
+        if let Some(v) = args.mode {
+            self.server.mode = v;
+        }
+        if let Some(v) = args.backend {
+            self.storage.backend = v;
+        }
+        if let Some(v) = &args.base_path {
+            self.storage.base_path = v.clone();
+        }
+        if let Some(v) = &args.temp_path {
+            self.storage.temp_path = v.clone();
+        }
+        if let Some(v) = args.max_file_size {
+            self.storage.max_file_size = v;
+        }
+        if let Some(v) = args.handshake_timeout {
+            self.timeouts.handshake = v;
+        }
+        if let Some(v) = args.idle_timeout {
+            self.timeouts.idle = v;
+        }
+        if let Some(v) = args.transaction_timeout {
+            self.timeouts.transaction = v;
+        }
+        if let Some(v) = args.min_transfer_rate {
+            self.timeouts.min_transfer_rate = v;
+        }
+        if let Some(v) = args.max_connections {
+            self.limits.max_connections = v;
+        }
+        if let Some(v) = args.max_connections_per_ip {
+            self.limits.max_connections_per_ip = v;
+        }
+        if let Some(v) = args.limit_policy {
+            self.limits.policy = v;
+        }
+        if let Some(v) = args.log_level {
+            self.log.level = v;
+        }
+        if let Some(v) = args.log_format {
+            self.log.format = v;
+        }
+        if let Some(v) = &args.access_log {
+            self.access_log.path = Some(v.clone());
+        }
+        if let Some(v) = args.metrics_listen {
+            self.metrics.listen = Some(v);
+        }
+        if let Some(v) = args.admin_listen {
+            self.admin.listen = Some(v);
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.server.pipeline_depth == 0 {
+            bail!("server.pipeline_depth: must be at least 1");
+        }
+        if self.metrics.storage_usage_interval.0 == 0 {
+            bail!("metrics.storage_usage_interval: must be at least 1s");
+        }
+        if self.hot_keys.capacity > 0 && self.hot_keys.window.0 == 0 {
+            bail!("hot_keys.window: must be at least 1s");
+        }
+        if let Some(dir) = &self.server.record_dir {
+            check_dir(dir).context("server.record_dir")?;
+        }
+        if let Some(path) = &self.access_log.path {
+            if path.as_os_str().is_empty() || path.is_dir() {
+                bail!("access_log.path: {:?} is not a file path", path);
+            }
+        }
+        let mut names = HashSet::new();
+        let mut addrs = HashSet::new();
+        // (listener, setting, normalized path) of every fs storage path
+        let mut fs_paths: Vec<(String, &str, PathBuf)> = Vec::new();
+        let mut all_systemd = false;
+        let mut named_systemd = false;
+        for listener in self.listeners() {
+            let name = &listener.name;
+            if name.is_empty() {
+                bail!("listener.name: must not be empty");
+            }
+            if !names.insert(name.clone()) {
+                bail!("listener {:?}: name is used by another listener", name);
+            }
+            if listener.listen.is_empty() {
+                bail!("listener {:?}: at least one listen address is required", name);
+            }
+            for addr in listener.listen.iter() {
+                let addr = addr.parse::<ListenAddr>()
+                    .map_err(|e| anyhow!("listener {:?}: invalid address {:?}: {}", name, addr, e))?;
+                match &addr {
+                    ListenAddr::Systemd(None) => all_systemd = true,
+                    ListenAddr::Systemd(Some(_)) => named_systemd = true,
+                    _ => {}
+                }
+                if !addrs.insert(addr.clone()) {
+                    bail!("listener {:?}: address {} is used by another listener", name, addr);
+                }
+            }
+            let storage = &listener.storage;
+            if storage.backend == Backend::Fs {
+                check_dir(&storage.base_path).with_context(|| format!("listener {:?}: storage.base_path", name))?;
+                check_dir(&storage.temp_path).with_context(|| format!("listener {:?}: storage.temp_path", name))?;
+                // listeners are separate namespaces. they must never see each other's files,
+                // so no path of a listener may be, or be inside, a path of another one.
+                for (setting, path) in [("base_path", &storage.base_path), ("temp_path", &storage.temp_path)] {
+                    let path = normalize_path(path);
+                    for (other, other_setting, other_path) in fs_paths.iter().filter(|(other, _, _)| other != name) {
+                        if path.starts_with(other_path) || other_path.starts_with(&path) {
+                            bail!("listener {:?}: storage.{} {} overlaps storage.{} {} of listener {:?}",
+                                  name, setting, path.display(), other_setting, other_path.display(), other);
+                        }
+                    }
+                    fs_paths.push((name.clone(), setting, path));
+                }
+            }
+            // anyone could claim any address, past the put allowlist and the per ip limits
+            if listener.proxy_protocol && listener.trusted_proxies.is_empty() {
+                bail!("listener {:?}: proxy_protocol requires trusted_proxies, the ip ranges of the load balancers", name);
+            }
+            if usize::try_from(storage.max_file_size.0).is_err() {
+                bail!("listener {:?}: storage.max_file_size {} is too large for this platform", name, storage.max_file_size);
+            }
+        }
+        if all_systemd && named_systemd {
+            bail!("listen: systemd takes every inherited socket. it can't be used with systemd:NAME");
+        }
+        Ok(())
+    }
+
+    /// the changed settings which only take effect after a restart
+    pub fn restart_required_changes(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+        let old_listeners = self.listeners();
+        let new_listeners = new.listeners();
+        for listener in old_listeners.iter() {
+            let name = &listener.name;
+            let new_listener = match new_listeners.iter().find(|new_listener| &new_listener.name == name) {
+                Some(new_listener) => new_listener,
+                None => {
+                    changes.push(format!("listener {:?} removed", name));
+                    continue;
+                }
+            };
+            if listener.listen != new_listener.listen {
+                changes.push(format!("listener {:?}: listen", name));
+            }
+            let (storage, new_storage) = (&listener.storage, &new_listener.storage);
+            if storage.backend != new_storage.backend || storage.base_path != new_storage.base_path || storage.temp_path != new_storage.temp_path {
+                changes.push(format!("listener {:?}: storage", name));
+            }
+            if listener.proxy_protocol != new_listener.proxy_protocol {
+                changes.push(format!("listener {:?}: proxy_protocol", name));
+            }
+        }
+        for new_listener in new_listeners.iter() {
+            if !old_listeners.iter().any(|listener| listener.name == new_listener.name) {
+                changes.push(format!("listener {:?} added", new_listener.name));
+            }
+        }
+        if self.server.record_dir != new.server.record_dir {
+            changes.push("server.record_dir".to_string());
+        }
+        if self.server.shutdown_grace_period != new.server.shutdown_grace_period {
+            changes.push("server.shutdown_grace_period".to_string());
+        }
+        if self.server.mode != new.server.mode {
+            changes.push("server.mode. SIGUSR2 switches the mode at runtime".to_string());
+        }
+        if self.log.format != new.log.format {
+            changes.push("log.format".to_string());
+        }
+        if self.log.stats_interval != new.log.stats_interval {
+            changes.push("log.stats_interval".to_string());
+        }
+        if self.access_log != new.access_log {
+            changes.push("access_log".to_string());
+        }
+        if self.metrics != new.metrics {
+            changes.push("metrics".to_string());
+        }
+        if self.admin != new.admin {
+            changes.push("admin".to_string());
+        }
+        if self.hot_keys != new.hot_keys {
+            changes.push("hot_keys".to_string());
+        }
+        changes
+    }
+
+    /// the listeners with the top-level storage and limits filled in
+    pub fn listeners(&self) -> Vec<ListenerSettings> {
+        if self.listeners.is_empty() {
+            return vec![ListenerSettings {
+                name: "default".to_string(),
+                listen: self.server.listen.clone(),
+                storage: self.storage.clone(),
+                limits: self.limits.clone(),
+                proxy_protocol: self.server.proxy_protocol,
+                trusted_proxies: self.server.trusted_proxies.clone(),
+                put_allowlist: self.server.put_allowlist.clone(),
+            }];
+        }
+        self.listeners.iter().map(|listener| ListenerSettings {
+            name: listener.name.clone(),
+            listen: listener.listen.clone(),
+            storage: listener.storage.clone().unwrap_or_else(|| self.storage.clone()),
+            limits: listener.limits.clone().unwrap_or_else(|| self.limits.clone()),
+            proxy_protocol: listener.proxy_protocol.unwrap_or(self.server.proxy_protocol),
+            trusted_proxies: listener.trusted_proxies.clone().unwrap_or_else(|| self.server.trusted_proxies.clone()),
+            put_allowlist: listener.put_allowlist.clone().unwrap_or_else(|| self.server.put_allowlist.clone()),
+        }).collect()
+    }
+
+    /// the session options of a listener
+    pub fn handle_options(&self, listener: &ListenerSettings) -> HandleOptions {
+        let mut options = HandleOptions::new();
+        options.set_max_file_size(listener.storage.max_file_size.0);
+        options.set_pipeline_depth(self.server.pipeline_depth);
+        options.set_handshake_timeout(self.timeouts.handshake.to_option());
+        options.set_idle_timeout(self.timeouts.idle.to_option());
+        options.set_transaction_timeout(self.timeouts.transaction.to_option());
+        options.set_min_transfer_rate(self.timeouts.min_transfer_rate.0);
+        options
+    }
+}
+
+/// a path used as a directory must not be an existing file
+/// absolute and lexically cleaned, so equal and nested paths can be found.
+/// symlinks are not resolved. the directories may not exist yet.
+fn normalize_path(path: &Path) -> PathBuf {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn check_dir(path: &Path) -> anyhow::Result<()> {
+    if path.as_os_str().is_empty() {
+        bail!("path is empty");
+    }
+    if path.exists() && !path.is_dir() {
+        bail!("{} is not a directory", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(u64),
+    String(String),
+}
+
+/// A size in bytes. Either a number or a string with a unit, e.g. `512`, `64KiB`, `256MiB`, `1g`
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "NumberOrString")]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_ascii_lowercase();
+        let (number, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => lower.split_at(i),
+            None => (lower.as_str(), ""),
+        };
+        let unit: u64 = match unit.trim() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            "t" | "tb" | "tib" => 1 << 40,
+            _ => return Err(format!("invalid size {:?}: unknown unit {:?}", s, unit)),
+        };
+        let number: u64 = number.parse().map_err(|e| format!("invalid size {:?}: {}", s, e))?;
+        number.checked_mul(unit).map(ByteSize).ok_or_else(|| format!("invalid size {:?}: too large", s))
+    }
+}
+
+impl TryFrom<NumberOrString> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: NumberOrString) -> Result<Self, Self::Error> {
+        match value {
+            NumberOrString::Number(n) => Ok(ByteSize(n)),
+            NumberOrString::String(s) => s.parse(),
+        }
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes", self.0)
+    }
+}
+
+/// A duration in whole seconds. Either a number of seconds or a string like `30s`, `10m`, `1h`
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "NumberOrString")]
+pub struct Seconds(pub u64);
+
+impl Seconds {
+    pub fn to_duration(self) -> Duration {
+        Duration::from_secs(self.0)
+    }
+
+    /// None for 0, which means no limit
+    pub fn to_option(self) -> Option<Duration> {
+        if self.0 == 0 {
+            None
+        } else {
+            Some(self.to_duration())
+        }
+    }
+}
+
+impl FromStr for Seconds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(n) = s.trim().parse::<u64>() {
+            return Ok(Seconds(n));
+        }
+        let d = humantime::parse_duration(s.trim()).map_err(|e| format!("invalid duration {:?}: {}", s, e))?;
+        // rounding "500ms" down would turn a timeout into 0, which means no limit
+        if d.subsec_nanos() != 0 {
+            return Err(format!("invalid duration {:?}: must be whole seconds", s));
+        }
+        Ok(Seconds(d.as_secs()))
+    }
+}
+
+impl TryFrom<NumberOrString> for Seconds {
+    type Error = String;
+
+    fn try_from(value: NumberOrString) -> Result<Self, Self::Error> {
+        match value {
+            NumberOrString::Number(n) => Ok(Seconds(n)),
+            NumberOrString::String(s) => s.parse(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Config {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn byte_size() {
+        assert_eq!("512".parse(), Ok(ByteSize(512)));
+        assert_eq!("0".parse(), Ok(ByteSize(0)));
+        assert_eq!("64KiB".parse(), Ok(ByteSize(64 << 10)));
+        assert_eq!(" 256 mb ".parse(), Ok(ByteSize(256 << 20)));
+        assert_eq!("1g".parse(), Ok(ByteSize(1 << 30)));
+        assert_eq!("2T".parse(), Ok(ByteSize(2 << 40)));
+        assert!("1.5m".parse::<ByteSize>().is_err());
+        assert!("10 parsecs".parse::<ByteSize>().is_err());
+        assert!("m".parse::<ByteSize>().is_err());
+        assert!("-1".parse::<ByteSize>().is_err());
+        assert!("99999999999t".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!("30".parse(), Ok(Seconds(30)));
+        assert_eq!("0".parse(), Ok(Seconds(0)));
+        assert_eq!("10m".parse(), Ok(Seconds(600)));
+        assert_eq!("1h 30m".parse(), Ok(Seconds(5400)));
+        assert_eq!("2000ms".parse(), Ok(Seconds(2)));
+        // would round down to 0, which is no limit
+        assert!("500ms".parse::<Seconds>().is_err());
+        assert!("1500ms".parse::<Seconds>().is_err());
+        assert!("soon".parse::<Seconds>().is_err());
+        assert_eq!(Seconds(0).to_option(), None);
+        assert_eq!(Seconds(5).to_option(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn numbers_and_strings_in_the_config_file() {
+        let config = parse("[timeouts]\nidle = 90\ntransaction = \"10m\"\n[storage]\nmax_file_size = \"1KiB\"\n");
+        assert_eq!(config.timeouts.idle, Seconds(90));
+        assert_eq!(config.timeouts.transaction, Seconds(600));
+        assert_eq!(config.storage.max_file_size, ByteSize(1024));
+        assert!(toml::from_str::<Config>("[timeouts]\nidle = \"100ms\"\n").is_err());
+    }
+
+    #[test]
+    fn listeners_fall_back_to_the_top_level_settings() {
+        let config = parse(r#"
+            [server]
+            proxy_protocol = true
+            trusted_proxies = ["192.168.0.10/32"]
+            put_allowlist = ["10.0.0.0/8"]
+            [storage]
+            base_path = "top"
+            temp_path = "top-temp"
+            [limits]
+            max_connections = 7
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:1"]
+            [[listener]]
+            name = "b"
+            listen = ["127.0.0.1:2"]
+            proxy_protocol = false
+            put_allowlist = []
+            [listener.storage]
+            base_path = "b"
+            temp_path = "b-temp"
+            [listener.limits]
+            max_connections = 3
+        "#);
+        config.validate().unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 2);
+        let (a, b) = (&listeners[0], &listeners[1]);
+        assert_eq!(a.storage.base_path, PathBuf::from("top"));
+        assert_eq!(a.limits.max_connections, 7);
+        assert!(a.proxy_protocol);
+        assert_eq!(a.trusted_proxies.len(), 1);
+        assert_eq!(a.put_allowlist.len(), 1);
+        assert_eq!(b.storage.base_path, PathBuf::from("b"));
+        assert_eq!(b.limits.max_connections, 3);
+        assert!(!b.proxy_protocol);
+        assert!(b.put_allowlist.is_empty());
+    }
+
+    #[test]
+    fn without_listeners_the_top_level_settings_are_served() {
+        let config = parse("[server]\nlisten = [\"127.0.0.1:1\", \"unix:/tmp/ucs.sock\"]\n");
+        config.validate().unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].name, "default");
+        assert_eq!(listeners[0].listen.len(), 2);
+    }
+
+    fn validate_error(content: &str) -> String {
+        format!("{:#}", parse(content).validate().unwrap_err())
+    }
+
+    #[test]
+    fn listeners_must_not_share_names_or_addresses() {
+        let error = validate_error(r#"
+            storage = { backend = "memory" }
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:1"]
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:2"]
+        "#);
+        assert!(error.contains("name is used by another listener"), "{}", error);
+
+        let error = validate_error(r#"
+            storage = { backend = "memory" }
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:1"]
+            [[listener]]
+            name = "b"
+            listen = ["127.0.0.1:1"]
+        "#);
+        assert!(error.contains("address 127.0.0.1:1 is used by another listener"), "{}", error);
+    }
+
+    /// the error of two listeners with the given fs storage paths
+    fn storage_error(a: (&str, &str), b: (&str, &str)) -> Option<String> {
+        let content = format!(r#"
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:1"]
+            storage = {{ base_path = "{}", temp_path = "{}" }}
+            [[listener]]
+            name = "b"
+            listen = ["127.0.0.1:2"]
+            storage = {{ base_path = "{}", temp_path = "{}" }}
+        "#, a.0, a.1, b.0, b.1);
+        parse(&content).validate().err().map(|e| format!("{:#}", e))
+    }
+
+    #[test]
+    fn listeners_must_not_share_or_nest_storage_paths() {
+        assert_eq!(storage_error(("a", "a"), ("b", "b")), None);
+        assert_eq!(storage_error(("cache/a", "temp/a"), ("cache/b", "temp/b")), None);
+        // a prefix of the name is not a parent directory
+        assert_eq!(storage_error(("cache", "temp"), ("cache-b", "temp-b")), None);
+
+        let error = storage_error(("a", "a"), ("a", "b")).unwrap();
+        assert!(error.contains("listener \"b\": storage.base_path") && error.contains("overlaps storage.base_path"), "{}", error);
+        let error = storage_error(("a", "temp"), ("b", "temp")).unwrap();
+        assert!(error.contains("storage.temp_path") && error.contains("overlaps storage.temp_path"), "{}", error);
+        // one listener's temp_path is another's base_path
+        let error = storage_error(("a", "b"), ("b", "b")).unwrap();
+        assert!(error.contains("storage.base_path") && error.contains("overlaps storage.temp_path"), "{}", error);
+        // nested in either direction
+        assert!(storage_error(("cache", "cache"), ("cache/b", "cache/b")).is_some());
+        assert!(storage_error(("cache/a", "cache/a"), ("cache", "temp")).is_some());
+        assert!(storage_error(("a", "a"), ("b", "a/temp")).is_some());
+        // spelled differently
+        assert!(storage_error(("a", "a"), ("./a/", "b")).is_some());
+        assert!(storage_error(("a", "a"), ("b/../a", "b")).is_some());
+        let absolute = std::env::current_dir().unwrap().join("a");
+        assert!(storage_error(("a", "a"), (absolute.to_str().unwrap(), "b")).is_some());
+
+        // only the fs backend has paths
+        parse(r#"
+            storage = { backend = "memory" }
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:1"]
+            [[listener]]
+            name = "b"
+            listen = ["127.0.0.1:2"]
+        "#).validate().unwrap();
+    }
+
+    #[test]
+    fn normalized_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(normalize_path(Path::new("a/./b/../c/")), cwd.join("a/c"));
+        assert_eq!(normalize_path(Path::new(".cache_fs")), cwd.join(".cache_fs"));
+        assert_eq!(normalize_path(&cwd.join("x/..")), cwd);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(validate_error("[server]\npipeline_depth = 0\n").contains("pipeline_depth"));
+        assert!(validate_error("[[listener]]\nname = \"\"\nlisten = [\"127.0.0.1:1\"]\n").contains("must not be empty"));
+        assert!(validate_error("[[listener]]\nname = \"a\"\nlisten = []\n").contains("at least one listen address"));
+        assert!(validate_error("[server]\nlisten = [\"nowhere\"]\n").contains("invalid address"));
+        assert!(validate_error("[server]\nlisten = [\"systemd\", \"systemd:a\"]\n").contains("systemd"));
+        assert!(validate_error("[server]\nproxy_protocol = true\n").contains("requires trusted_proxies"));
+    }
+
+    #[test]
+    fn proxy_header_is_only_read_from_trusted_proxies() {
+        let config = parse("[server]\nproxy_protocol = true\ntrusted_proxies = [\"10.0.0.0/24\", \"fd00::1/128\"]\n");
+        config.validate().unwrap();
+        let listener = &config.listeners()[0];
+        assert!(listener.is_trusted_proxy(Some("10.0.0.7".parse().unwrap())));
+        assert!(listener.is_trusted_proxy(Some("::ffff:10.0.0.7".parse().unwrap())));
+        assert!(listener.is_trusted_proxy(Some("fd00::1".parse().unwrap())));
+        assert!(!listener.is_trusted_proxy(Some("10.0.1.7".parse().unwrap())));
+        assert!(!listener.is_trusted_proxy(None));
+
+        let config = parse("[server]\ntrusted_proxies = [\"10.0.0.0/24\"]\n");
+        assert!(!config.listeners()[0].is_trusted_proxy(Some("10.0.0.7".parse().unwrap())));
+    }
+
+    #[test]
+    fn puts_are_only_allowed_from_the_allowlist() {
+        let config = parse("[server]\nput_allowlist = [\"10.0.0.0/24\"]\n");
+        let listener = &config.listeners()[0];
+        assert!(listener.is_put_allowed(Some("10.0.0.7".parse().unwrap())));
+        assert!(listener.is_put_allowed(Some("::ffff:10.0.0.7".parse().unwrap())));
+        assert!(!listener.is_put_allowed(Some("10.0.1.7".parse().unwrap())));
+        assert!(!listener.is_put_allowed(None));
+
+        let config = parse("[server]\n");
+        let listener = &config.listeners()[0];
+        assert!(listener.is_put_allowed(Some("10.0.1.7".parse().unwrap())));
+        assert!(listener.is_put_allowed(None));
+    }
+
+    #[test]
+    fn reload_takes_the_settings_which_can_change_at_runtime() {
+        let old = parse("[server]\nproxy_protocol = true\ntrusted_proxies = [\"10.0.0.10/32\"]\n[storage]\nbase_path = \"old\"\nmax_file_size = \"1KiB\"\n");
+        let new = parse(r#"
+            [server]
+            proxy_protocol = false
+            trusted_proxies = ["10.0.0.11/32"]
+            put_allowlist = ["10.0.0.0/8"]
+            [storage]
+            base_path = "new"
+            max_file_size = "2KiB"
+            [limits]
+            max_connections = 7
+            max_connections_per_ip = 2
+            policy = "reject"
+        "#);
+        let mut settings = old.listeners().remove(0);
+        settings.reload(&new.listeners()[0]);
+        assert_eq!(settings.storage.max_file_size, ByteSize(2048));
+        assert_eq!(settings.limits.max_connections, 7);
+        assert_eq!(settings.limits.max_connections_per_ip, 2);
+        assert_eq!(settings.limits.policy, LimitPolicyConfig::Reject);
+        assert_eq!(settings.trusted_proxies, vec!["10.0.0.11/32".parse::<IpNet>().unwrap()]);
+        assert_eq!(settings.put_allowlist, vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]);
+        // these need a restart
+        assert_eq!(settings.storage.base_path, PathBuf::from("old"));
+        assert!(settings.proxy_protocol);
+    }
+
+    #[test]
+    fn changes_which_need_a_restart_are_reported() {
+        let old = parse(r#"
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:1"]
+            [listener.storage]
+            base_path = "a"
+            [[listener]]
+            name = "b"
+            listen = ["127.0.0.1:2"]
+            [listener.storage]
+            base_path = "b"
+        "#);
+        assert!(old.restart_required_changes(&old).is_empty());
+
+        let new = parse(r#"
+            [server]
+            record_dir = "recordings"
+            [limits]
+            max_connections = 7
+            [[listener]]
+            name = "a"
+            listen = ["127.0.0.1:3"]
+            proxy_protocol = true
+            trusted_proxies = ["10.0.0.10/32"]
+            [listener.storage]
+            base_path = "a2"
+            max_file_size = "1KiB"
+            [[listener]]
+            name = "c"
+            listen = ["127.0.0.1:2"]
+            [listener.storage]
+            base_path = "c"
+        "#);
+        assert_eq!(old.restart_required_changes(&new), vec![
+            "listener \"a\": listen",
+            "listener \"a\": storage",
+            "listener \"a\": proxy_protocol",
+            "listener \"b\" removed",
+            "listener \"c\" added",
+            "server.record_dir",
+        ]);
+
+        let new = parse("[storage]\nbackend = \"memory\"\n");
+        assert_eq!(parse("").restart_required_changes(&new), vec!["listener \"default\": storage"]);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{CommandKind, CommandObserver, CommandRecord};
+use crate::shutdown::{Shutdown, ShutdownSignal};
+
+/// The connections being served, for the admin API
+#[derive(Debug, Default)]
+pub struct Connections {
+    entries: Mutex<BTreeMap<u64, Arc<ConnectionInfo>>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// add a connection until the registration is dropped
+    pub fn register(self: &Arc<Self>, id: u64, listener: &str, peer: &str) -> Registration {
+        let info = Arc::new(ConnectionInfo {
+            id,
+            listener: listener.to_string(),
+            peer: peer.to_string(),
+            started_at: SystemTime::now(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            command: Mutex::new(CurrentCommand::default()),
+            close: Shutdown::new(),
+        });
+        self.entries.lock().unwrap().insert(id, info.clone());
+        Registration {
+            connections: self.clone(),
+            info,
+        }
+    }
+
+    /// the connections, oldest first
+    pub fn list(&self) -> Vec<Arc<ConnectionInfo>> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<ConnectionInfo>> {
+        self.entries.lock().unwrap().get(&id).cloned()
+    }
+}
+
+/// Remove the connection from the registry on drop
+#[derive(Debug)]
+pub struct Registration {
+    connections: Arc<Connections>,
+    info: Arc<ConnectionInfo>,
+}
+
+impl Registration {
+    pub fn info(&self) -> &Arc<ConnectionInfo> {
+        &self.info
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.entries.lock().unwrap().remove(&self.info.id);
+    }
+}
+
+#[derive(Debug, Default)]
+struct CurrentCommand {
+    kind: Option<CommandKind>,
+    since: Option<SystemTime>,
+    /// pipelined gets run together
+    in_flight: usize,
+}
+
+/// What a connection is doing. It observes its session to know the current command.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    id: u64,
+    listener: String,
+    /// the client address, from the PROXY protocol header if there is one
+    peer: String,
+    started_at: SystemTime,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    command: Mutex<CurrentCommand>,
+    close: Shutdown,
+}
+
+impl ConnectionInfo {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn listener(&self) -> &str {
+        &self.listener
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// bytes received from the client, including the protocol
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// bytes sent to the client, including the protocol
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// the command being served and since when. None if the session waits for the next command.
+    pub fn current_command(&self) -> Option<(CommandKind, SystemTime)> {
+        let command = self.command.lock().unwrap();
+        command.kind.zip(command.since)
+    }
+
+    /// signal for [`crate::HandleOptions::set_close`]
+    pub fn close_signal(&self) -> ShutdownSignal {
+        self.close.signal()
+    }
+
+    /// cancel the open transaction and close the connection
+    pub fn close(&self) {
+        self.close.terminate();
+    }
+}
+
+impl CommandObserver for ConnectionInfo {
+    fn on_command_started(&self, kind: CommandKind) {
+        let mut command = self.command.lock().unwrap();
+        if command.in_flight == 0 {
+            command.kind = Some(kind);
+            command.since = Some(SystemTime::now());
+        }
+        command.in_flight += 1;
+    }
+
+    fn on_command(&self, _record: CommandRecord) {
+        let mut command = self.command.lock().unwrap();
+        command.in_flight = command.in_flight.saturating_sub(1);
+        if command.in_flight == 0 {
+            command.kind = None;
+            command.since = None;
+        }
+    }
+}
+
+/// Counts the bytes moved through a connection into its [`ConnectionInfo`]
+#[derive(Debug)]
+pub struct CountingStream<S> {
+    inner: S,
+    info: Arc<ConnectionInfo>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S, info: Arc<ConnectionInfo>) -> Self {
+        Self {
+            inner,
+            info,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.info.bytes_read.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.info.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::CommandOutcome;
+
+    use super::*;
+
+    fn record(kind: CommandKind) -> CommandRecord {
+        CommandRecord {
+            kind,
+            file_type: None,
+            guid: None,
+            hash: None,
+            outcome: CommandOutcome::Ok,
+            bytes: 0,
+            started_at: SystemTime::now(),
+            duration: Default::default(),
+            handler_time: None,
+        }
+    }
+
+    #[test]
+    fn current_command_lasts_until_the_pipelined_commands_end() {
+        let connections = Arc::new(Connections::new());
+        let registration = connections.register(1, "default", "10.0.0.5:1234");
+        let info = registration.info();
+        assert_eq!(info.current_command(), None);
+        info.on_command_started(CommandKind::Get);
+        let (kind, since) = info.current_command().unwrap();
+        assert_eq!(kind, CommandKind::Get);
+        info.on_command_started(CommandKind::Get);
+        info.on_command(record(CommandKind::Get));
+        assert_eq!(info.current_command(), Some((CommandKind::Get, since)));
+        info.on_command(record(CommandKind::Get));
+        assert_eq!(info.current_command(), None);
+        info.on_command_started(CommandKind::Put);
+        assert_eq!(info.current_command().unwrap().0, CommandKind::Put);
+    }
+
+    #[test]
+    fn registration_lasts_until_dropped() {
+        let connections = Arc::new(Connections::new());
+        let first = connections.register(1, "a", "10.0.0.5:1234");
+        let second = connections.register(2, "b", "/tmp/ucs.sock");
+        let ids: Vec<u64> = connections.list().iter().map(|info| info.id()).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(connections.get(2).unwrap().listener(), "b");
+        drop(second);
+        assert!(connections.get(2).is_none());
+        assert_eq!(connections.list().len(), 1);
+        drop(first);
+        assert!(connections.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn counting_stream_counts_both_directions() {
+        let connections = Arc::new(Connections::new());
+        let registration = connections.register(1, "default", "10.0.0.5:1234");
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = CountingStream::new(server, registration.info().clone());
+        client.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"reply").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        let info = registration.info();
+        assert_eq!(info.bytes_read(), 7);
+        assert_eq!(info.bytes_written(), 5);
+    }
+}
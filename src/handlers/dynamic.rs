@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+
+use crate::{Handler, Result, StorageUsage, UnityFileGuid, UnityFileHash, UnityFileType};
+
+/// file returned by a type-erased handler
+pub type DynFile = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Object-safe version of [`Handler`].
+/// Every `Handler + Clone` implements it, so any backend can be boxed into a [`BoxedHandler`].
+#[async_trait]
+pub trait DynHandler: Send + Sync {
+    async fn version(&self, version: u32) -> Result<u32>;
+
+    async fn get(&self, t: UnityFileType, guid: &UnityFileGuid, hash: &UnityFileHash) -> Result<Option<(u64, DynFile)>>;
+
+    async fn start_transaction(&mut self, guid: UnityFileGuid, hash: UnityFileHash) -> Result<()>;
+
+    async fn end_transaction(&mut self) -> Result<()>;
+
+    async fn cancel_transaction(&mut self) -> Result<()>;
+
+    async fn put(&mut self, t: UnityFileType, size: u64, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()>;
+
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>>;
+
+    async fn cleanup(&self, older_than: Duration) -> Result<u64>;
+
+    /// clone the handler for a new connection
+    fn clone_box(&self) -> Box<dyn DynHandler>;
+}
+
+#[async_trait]
+impl<H> DynHandler for H
+    where
+        H: Handler + Clone + Send + 'static,
+        H::File: Send + 'static,
+{
+    async fn version(&self, version: u32) -> Result<u32> {
+        Handler::version(self, version).await
+    }
+
+    async fn get(&self, t: UnityFileType, guid: &UnityFileGuid, hash: &UnityFileHash) -> Result<Option<(u64, DynFile)>> {
+        let file = Handler::get(self, t, guid, hash).await?;
+        Ok(file.map(|(size, file)| (size, Box::new(file) as DynFile)))
+    }
+
+    async fn start_transaction(&mut self, guid: UnityFileGuid, hash: UnityFileHash) -> Result<()> {
+        Handler::start_transaction(self, guid, hash).await
+    }
+
+    async fn end_transaction(&mut self) -> Result<()> {
+        Handler::end_transaction(self).await
+    }
+
+    async fn cancel_transaction(&mut self) -> Result<()> {
+        Handler::cancel_transaction(self).await
+    }
+
+    async fn put(&mut self, t: UnityFileType, size: u64, reader: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        Handler::put(self, t, size, reader).await
+    }
+
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>> {
+        Handler::storage_usage(self).await
+    }
+
+    async fn cleanup(&self, older_than: Duration) -> Result<u64> {
+        Handler::cleanup(self, older_than).await
+    }
+
+    fn clone_box(&self) -> Box<dyn DynHandler> {
+        Box::new(self.clone())
+    }
+}
+
+/// A handler whose backend is chosen at runtime
+pub struct BoxedHandler(Box<dyn DynHandler>);
+
+impl BoxedHandler {
+    pub fn new<H>(handler: H) -> Self
+        where
+            H: Handler + Clone + Send + 'static,
+            H::File: Send + 'static,
+    {
+        Self(Box::new(handler))
+    }
+}
+
+impl From<Box<dyn DynHandler>> for BoxedHandler {
+    fn from(handler: Box<dyn DynHandler>) -> Self {
+        Self(handler)
+    }
+}
+
+impl Clone for BoxedHandler {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+#[async_trait]
+impl Handler for BoxedHandler {
+    type File = DynFile;
+
+    async fn version(&self, version: u32) -> Result<u32> {
+        self.0.version(version).await
+    }
+
+    async fn get(&self, t: UnityFileType, guid: &UnityFileGuid, hash: &UnityFileHash) -> Result<Option<(u64, Self::File)>> {
+        self.0.get(t, guid, hash).await
+    }
+
+    async fn start_transaction(&mut self, guid: UnityFileGuid, hash: UnityFileHash) -> Result<()> {
+        self.0.start_transaction(guid, hash).await
+    }
+
+    async fn end_transaction(&mut self) -> Result<()> {
+        self.0.end_transaction().await
+    }
+
+    async fn cancel_transaction(&mut self) -> Result<()> {
+        self.0.cancel_transaction().await
+    }
+
+    async fn put<R: AsyncRead + Unpin + Send>(&mut self, t: UnityFileType, size: u64, mut reader: R) -> Result<()> {
+        self.0.put(t, size, &mut reader).await
+    }
+
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>> {
+        self.0.storage_usage().await
+    }
+
+    async fn cleanup(&self, older_than: Duration) -> Result<u64> {
+        self.0.cleanup(older_than).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::{Handler, HexString, UnityFileType};
+    use crate::handlers::MemoryHandler;
+
+    // DynHandler is left out, so the calls go through the Handler impl of BoxedHandler
+    use super::BoxedHandler;
+
+    async fn read(handler: &BoxedHandler, t: UnityFileType, n: u8) -> Option<Vec<u8>> {
+        let (size, mut file) = handler.get(t, &HexString([n; 16]), &HexString([n; 16])).await.unwrap()?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).await.unwrap();
+        assert_eq!(size, content.len() as u64);
+        Some(content)
+    }
+
+    #[tokio::test]
+    async fn boxed_memory_handler_round_trip() {
+        let memory = MemoryHandler::new();
+        let mut handler = BoxedHandler::new(memory.clone());
+        assert_eq!(handler.version(254).await.unwrap(), 254);
+
+        handler.start_transaction(HexString([1; 16]), HexString([1; 16])).await.unwrap();
+        handler.put(UnityFileType::Asset, 5, &b"asset"[..]).await.unwrap();
+        handler.put(UnityFileType::Info, 4, &b"info"[..]).await.unwrap();
+        handler.end_transaction().await.unwrap();
+        assert_eq!(read(&handler, UnityFileType::Asset, 1).await.as_deref(), Some(&b"asset"[..]));
+        assert_eq!(read(&handler, UnityFileType::Info, 1).await.as_deref(), Some(&b"info"[..]));
+        assert_eq!(read(&handler, UnityFileType::Resource, 1).await, None);
+
+        handler.start_transaction(HexString([2; 16]), HexString([2; 16])).await.unwrap();
+        handler.put(UnityFileType::Asset, 5, &b"asset"[..]).await.unwrap();
+        handler.cancel_transaction().await.unwrap();
+        assert_eq!(read(&handler, UnityFileType::Asset, 2).await, None);
+
+        // clones share the backend
+        assert_eq!(read(&handler.clone(), UnityFileType::Asset, 1).await.as_deref(), Some(&b"asset"[..]));
+        assert_eq!(memory.file_count().await, 2);
+    }
+}
@@ -1,20 +1,52 @@
+use std::collections::HashSet;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, BufWriter};
 use tokio::sync::Mutex;
+use tracing::warn;
 
-use crate::{Error, Handler, Result, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::{Error, Handler, Result, StorageUsage, UnityFileGuid, UnityFileHash, UnityFileType};
 use crate::handlers::Transaction;
 
+/// paths of the temp files in use, shared by the clones of a handler
+type OpenTempFiles = Arc<std::sync::Mutex<HashSet<PathBuf>>>;
+
+/// a temp file path in [`OpenTempFiles`] until dropped
+#[derive(Debug)]
+struct Registration {
+    open_files: OpenTempFiles,
+    path: PathBuf,
+}
+
+impl Registration {
+    fn new(open_files: &OpenTempFiles, path: PathBuf) -> Self {
+        open_files.lock().unwrap().insert(path.clone());
+        Self {
+            open_files: open_files.clone(),
+            path,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.open_files.lock().unwrap().remove(&self.path);
+    }
+}
+
 #[derive(Debug)]
 pub struct TempFile {
     path: Option<PathBuf>,
     writer: Option<BufWriter<File>>,
+    /// keeps the file from the cleanup. dropped after the file is moved or removed.
+    registration: Option<Registration>,
 }
 
 impl TempFile {
@@ -27,6 +59,7 @@ impl TempFile {
         Ok(Self {
             path: Some(path_buf),
             writer: Some(BufWriter::new(file)),
+            registration: None,
         })
     }
 
@@ -39,16 +72,26 @@ impl TempFile {
         tokio::fs::rename(self.path.take().unwrap(), to).await?;
         Ok(())
     }
+
+    /// remove the file without blocking the runtime
+    pub async fn remove(mut self) {
+        self.writer = None;
+        if let Some(path) = self.path.take() {
+            tokio::fs::remove_file(&path).await.unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "remove temp file error");
+            });
+        }
+    }
 }
 
 impl Drop for TempFile {
+    /// fallback for the files which are neither moved nor removed, e.g. a file replaced by another put of its type.
+    /// the file is removed before drop returns. a removal spawned on the runtime could be cancelled by a shutdown.
     fn drop(&mut self) {
         self.writer = None;
         if let Some(path) = self.path.take() {
-            tokio::spawn(async move {
-                tokio::fs::remove_file(&path).await.unwrap_or_else(|e| {
-                    println!("remove temp file {} error {:?}", path.to_string_lossy(), e);
-                });
+            std::fs::remove_file(&path).unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "remove temp file error");
             });
         }
     }
@@ -76,6 +119,8 @@ pub struct FileSystemHandler {
     transaction: Mutex<Option<Transaction<TempFile>>>,
     base_path: PathBuf,
     temp_path: PathBuf,
+    /// the cleanup must not remove the files of transactions waiting for their end
+    open_temp_files: OpenTempFiles,
 }
 
 impl FileSystemHandler {
@@ -85,6 +130,7 @@ impl FileSystemHandler {
             transaction: Default::default(),
             base_path,
             temp_path,
+            open_temp_files: Default::default(),
         }
     }
 
@@ -108,17 +154,22 @@ impl FileSystemHandler {
 
     pub async fn new_tmp_file(&self) -> std::io::Result<TempFile> {
         let path = self.temp_path.join(uuid::Uuid::new_v4().to_string());
-        TempFile::open(path).await
+        // registered before the file exists, so the cleanup never sees it unregistered
+        let registration = Registration::new(&self.open_temp_files, path.clone());
+        let mut file = TempFile::open(path).await?;
+        file.registration = Some(registration);
+        Ok(file)
     }
 }
 
 impl Clone for FileSystemHandler {
     fn clone(&self) -> Self {
         Self {
-            max_file_size: self.max_file_size.clone(),
+            max_file_size: self.max_file_size,
             transaction: Default::default(),
             base_path: self.base_path.clone(),
             temp_path: self.temp_path.clone(),
+            open_temp_files: self.open_temp_files.clone(),
         }
     }
 }
@@ -145,7 +196,10 @@ impl Handler for FileSystemHandler {
     }
 
     async fn start_transaction(&mut self, guid: UnityFileGuid, hash: UnityFileHash) -> Result<()> {
-        *self.transaction.lock().await = Some(Transaction::new(guid, hash));
+        let previous = self.transaction.lock().await.replace(Transaction::new(guid, hash));
+        if let Some(previous) = previous {
+            remove_files(previous).await;
+        }
         Ok(())
     }
 
@@ -163,29 +217,206 @@ impl Handler for FileSystemHandler {
     }
 
     async fn cancel_transaction(&mut self) -> Result<()> {
-        *self.transaction.lock().await = None;
+        let transaction = self.transaction.lock().await.take();
+        if let Some(transaction) = transaction {
+            remove_files(transaction).await;
+        }
         Ok(())
     }
 
     async fn put<R: AsyncRead + Unpin + Send>(&mut self, t: UnityFileType, size: u64, reader: R) -> Result<()> {
-        if self.max_file_size != 0 {
-            if size > self.max_file_size as u64 {
-                return Err(Error::FileTooLarge {
-                    max_size: self.max_file_size,
-                    size: size as usize,
-                });
-            }
+        if self.max_file_size != 0 && size > self.max_file_size as u64 {
+            return Err(Error::FileTooLarge {
+                max_size: self.max_file_size,
+                size: size as usize,
+            });
         }
         let mut temp_file = self.new_tmp_file().await?;
-        let n = tokio::io::copy(&mut reader.take(size), &mut temp_file).await?;
+        let n = match tokio::io::copy(&mut reader.take(size), &mut temp_file).await {
+            Ok(n) => n,
+            Err(e) => {
+                temp_file.remove().await;
+                return Err(Error::IoError(e));
+            }
+        };
         if n != size {
+            temp_file.remove().await;
             return Err(Error::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
         }
         if let Some(transaction) = &mut *self.transaction.lock().await {
             transaction.files.set(t, temp_file);
-            Ok(())
-        } else {
-            Err(Error::NotInTransaction)
+            return Ok(());
         }
+        temp_file.remove().await;
+        Err(Error::NotInTransaction)
     }
-}
\ No newline at end of file
+
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>> {
+        let base_path = self.base_path.clone();
+        let usage = tokio::task::spawn_blocking(move || dir_usage(&base_path)).await
+            .map_err(|e| Error::HandlerError(e.to_string()))??;
+        Ok(Some(usage))
+    }
+
+    async fn cleanup(&self, older_than: Duration) -> Result<u64> {
+        let temp_path = self.temp_path.clone();
+        let open_temp_files = self.open_temp_files.clone();
+        let removed = tokio::task::spawn_blocking(move || remove_temp_files(&temp_path, &open_temp_files, older_than)).await
+            .map_err(|e| Error::HandlerError(e.to_string()))??;
+        Ok(removed)
+    }
+}
+
+/// remove the temp files not modified for `older_than`.
+/// the files still used by an upload or a transaction of this handler are kept, however old they are.
+fn remove_temp_files(temp_path: &Path, open_temp_files: &OpenTempFiles, older_than: Duration) -> std::io::Result<u64> {
+    let entries = match std::fs::read_dir(temp_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        // temp files are named by a uuid. the temp path may be the base path.
+        if uuid::Uuid::parse_str(&entry.file_name().to_string_lossy()).is_err() {
+            continue;
+        }
+        if open_temp_files.lock().unwrap().contains(&entry.path()) {
+            continue;
+        }
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let age = meta.modified().ok().and_then(|modified| now.duration_since(modified).ok()).unwrap_or_default();
+        if meta.is_file() && age >= older_than {
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// remove the temp files of a transaction which is not committed
+async fn remove_files(mut transaction: Transaction<TempFile>) {
+    for (_, file) in transaction.files.take_all() {
+        file.remove().await;
+    }
+}
+
+/// sum the files in the hash directories. temp files are directly in the base path, so they are not counted.
+fn dir_usage(base_path: &Path) -> std::io::Result<StorageUsage> {
+    let mut usage = StorageUsage::default();
+    let dirs = match std::fs::read_dir(base_path) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(usage),
+        Err(e) => return Err(e),
+    };
+    for dir in dirs {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(dir.path())? {
+            let meta = match file?.metadata() {
+                Ok(meta) => meta,
+                // removed while walking
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if meta.is_file() {
+                usage.files += 1;
+                usage.bytes += meta.len();
+            }
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::HexString;
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ucs-fs-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn file_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir).map(|entries| entries.count()).unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn cancelled_transaction_removes_its_temp_files() {
+        let dir = temp_dir();
+        let mut handler = FileSystemHandler::new(dir.join("data"), dir.join("temp"));
+        handler.start_transaction(HexString([1; 16]), HexString([1; 16])).await.unwrap();
+        handler.put(UnityFileType::Asset, 5, &b"asset"[..]).await.unwrap();
+        handler.put(UnityFileType::Info, 4, &b"info"[..]).await.unwrap();
+        assert_eq!(file_count(&dir.join("temp")), 2);
+        handler.cancel_transaction().await.unwrap();
+        assert_eq!(file_count(&dir.join("temp")), 0);
+        assert_eq!(file_count(&dir.join("data")), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_put_removes_its_temp_file() {
+        let dir = temp_dir();
+        let mut handler = FileSystemHandler::new(dir.join("data"), dir.join("temp"));
+        handler.start_transaction(HexString([1; 16]), HexString([1; 16])).await.unwrap();
+        assert!(matches!(handler.put(UnityFileType::Asset, 10, &b"short"[..]).await, Err(Error::IoError(_))));
+        assert_eq!(file_count(&dir.join("temp")), 0);
+        handler.cancel_transaction().await.unwrap();
+        assert!(matches!(handler.put(UnityFileType::Asset, 5, &b"asset"[..]).await, Err(Error::NotInTransaction)));
+        assert_eq!(file_count(&dir.join("temp")), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_temp_file_is_removed_at_once() {
+        let dir = temp_dir();
+        let handler = FileSystemHandler::new(dir.join("data"), dir.join("temp"));
+        let mut file = handler.new_tmp_file().await.unwrap();
+        file.write_all(b"partial").await.unwrap();
+        let path = file.path.clone().unwrap();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cleanup_keeps_the_files_of_open_transactions() {
+        let dir = temp_dir();
+        let mut handler = FileSystemHandler::new(dir.join("data"), dir.join("temp"));
+        let (guid, hash) = (HexString([1; 16]), HexString([2; 16]));
+        handler.start_transaction(guid, hash).await.unwrap();
+        handler.put(UnityFileType::Asset, 5, &b"asset"[..]).await.unwrap();
+        let stale = dir.join("temp").join(uuid::Uuid::new_v4().to_string());
+        std::fs::write(&stale, b"left by a crash").unwrap();
+        let other = dir.join("temp").join("not-a-temp-file");
+        std::fs::write(&other, b"kept").unwrap();
+
+        // a session of the same listener
+        let cleaner = handler.clone();
+        assert_eq!(cleaner.cleanup(Duration::ZERO).await.unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(other.exists());
+
+        handler.end_transaction().await.unwrap();
+        let (size, _) = handler.get(UnityFileType::Asset, &guid, &hash).await.unwrap().unwrap();
+        assert_eq!(size, 5);
+        assert!(handler.open_temp_files.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
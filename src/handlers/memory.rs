@@ -8,7 +8,7 @@ use tokio::io;
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 use tokio::sync::Mutex;
 
-use crate::{Error, Handler, Result, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::{Error, Handler, Result, StorageUsage, UnityFileGuid, UnityFileHash, UnityFileType};
 use crate::handlers::Transaction;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -85,7 +85,7 @@ impl Handler for MemoryHandler {
         if let Some(mut transaction) = transaction {
             let mut guard = self.database.lock().await;
             for (t, file) in transaction.files.take_all() {
-                guard.insert(CacheKey::new(transaction.guid.clone(), transaction.hash.clone(), t), Bytes::from(file));
+                guard.insert(CacheKey::new(transaction.guid, transaction.hash, t), Bytes::from(file));
             }
         }
         Ok(())
@@ -97,13 +97,11 @@ impl Handler for MemoryHandler {
     }
 
     async fn put<R: AsyncRead + Unpin + Send>(&mut self, t: UnityFileType, size: u64, reader: R) -> Result<()> {
-        if self.max_file_size != 0 {
-            if size > self.max_file_size as u64 {
-                return Err(Error::FileTooLarge {
-                    max_size: self.max_file_size,
-                    size: size as usize,
-                });
-            }
+        if self.max_file_size != 0 && size > self.max_file_size as u64 {
+            return Err(Error::FileTooLarge {
+                max_size: self.max_file_size,
+                size: size as usize,
+            });
         }
         let mut buf = Vec::with_capacity(size as usize);
         let n = io::copy(&mut reader.take(size), &mut buf).await?;
@@ -117,4 +115,12 @@ impl Handler for MemoryHandler {
             Err(Error::NotInTransaction)
         }
     }
+
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>> {
+        let database = self.database.lock().await;
+        Ok(Some(StorageUsage {
+            files: database.len() as u64,
+            bytes: database.values().map(|file| file.len() as u64).sum(),
+        }))
+    }
 }
\ No newline at end of file
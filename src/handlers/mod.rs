@@ -1,12 +1,14 @@
 pub use memory::MemoryHandler;
 pub use nop::NopHandler;
 pub use fs::FileSystemHandler;
+pub use dynamic::{BoxedHandler, DynFile, DynHandler};
 
 use crate::{UnityFileGuid, UnityFileHash, UnityFileType};
 
 mod nop;
 mod memory;
 mod fs;
+mod dynamic;
 
 #[derive(Debug)]
 pub struct TransactionFiles<T>(Vec<Option<T>>);
@@ -19,7 +21,7 @@ impl<T> Default for TransactionFiles<T> {
 
 impl<T> TransactionFiles<T> {
     pub fn new() -> Self {
-        Self((0..UnityFileType::LENGTH).into_iter().map(|_| None).collect())
+        Self((0..UnityFileType::LENGTH).map(|_| None).collect())
     }
 
     pub fn take(&mut self, typ: UnityFileType) -> Option<T> {
@@ -2,7 +2,7 @@ use async_trait::async_trait;
 use tokio::io;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::{Handler, Result, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::{Handler, Result, StorageUsage, UnityFileGuid, UnityFileHash, UnityFileType};
 
 #[derive(Debug, Default, Clone)]
 pub struct NopHandler;
@@ -29,4 +29,8 @@ impl Handler for NopHandler {
         io::copy(&mut reader.take(size), &mut io::sink()).await?;
         Ok(())
     }
+
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>> {
+        Ok(Some(StorageUsage::default()))
+    }
 }
@@ -0,0 +1,291 @@
+//! Approximate top-N keys over a sliding window.
+//!
+//! Every bucket of the window keeps a space-saving sketch (Metwally et al.) of a bounded number of counters.
+//! A key missing from a full sketch takes over the smallest counter, so the counts of rarely seen keys are
+//! overestimated by at most `error`, and keys counted more often than that are never lost.
+//! The counters are also ordered by count, so finding the smallest one doesn't scan the sketch.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{CommandKind, CommandObserver, CommandRecord, UnityFileGuid, UnityFileHash, UnityFileType};
+
+/// Number of buckets the window is split into. The window slides one bucket at a time.
+const BUCKETS: u32 = 6;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct HotKey {
+    pub file_type: UnityFileType,
+    pub guid: UnityFileGuid,
+    pub hash: UnityFileHash,
+}
+
+impl Display for HotKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}.{}", self.guid, self.hash, self.file_type.to_ext())
+    }
+}
+
+/// A key and its approximate count
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HotKeyCount {
+    pub key: HotKey,
+    /// requests or bytes. may be overestimated by up to `error`.
+    pub count: u64,
+    pub error: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Counter {
+    count: u64,
+    error: u64,
+    /// tells apart the counters with the same count in `SpaceSaving::by_count`
+    id: u64,
+}
+
+/// space-saving sketch of at most `capacity` keys
+#[derive(Debug)]
+struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<HotKey, Counter>,
+    /// the keys by (count, id), smallest first
+    by_count: BTreeMap<(u64, u64), HotKey>,
+    next_id: u64,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+            by_count: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn add(&mut self, key: HotKey, weight: u64) {
+        if let Some(counter) = self.counters.get_mut(&key) {
+            self.by_count.remove(&(counter.count, counter.id));
+            counter.count += weight;
+            self.by_count.insert((counter.count, counter.id), key);
+            return;
+        }
+        let mut counter = Counter { count: weight, error: 0, id: self.next_id };
+        self.next_id += 1;
+        if self.counters.len() >= self.capacity {
+            // the new key takes over the smallest counter
+            match self.by_count.pop_first() {
+                Some(((smallest, _), smallest_key)) => {
+                    self.counters.remove(&smallest_key);
+                    counter.count += smallest;
+                    counter.error = smallest;
+                }
+                None => return,
+            }
+        }
+        self.by_count.insert((counter.count, counter.id), key);
+        self.counters.insert(key, counter);
+    }
+}
+
+/// the sketches of one bucket
+#[derive(Debug)]
+struct Bucket {
+    index: u64,
+    requests: SpaceSaving,
+    bytes: SpaceSaving,
+}
+
+/// Tracks the most requested keys and the keys with the most bytes transferred, over a sliding window.
+/// Gets count as requests. Gets and puts count their bytes.
+#[derive(Debug)]
+pub struct HotKeys {
+    capacity: usize,
+    window: Duration,
+    started: Instant,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl HotKeys {
+    /// keep up to `capacity` keys per bucket of the window
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            capacity,
+            window,
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn add(&self, key: HotKey, requests: u64, bytes: u64) {
+        self.add_at(key, requests, bytes, Instant::now())
+    }
+
+    fn add_at(&self, key: HotKey, requests: u64, bytes: u64, now: Instant) {
+        let index = self.bucket_index(now);
+        let mut buckets = self.buckets.lock().unwrap();
+        self.expire(&mut buckets, index);
+        if buckets.back().map(|bucket| bucket.index) != Some(index) {
+            buckets.push_back(Bucket {
+                index,
+                requests: SpaceSaving::new(self.capacity),
+                bytes: SpaceSaving::new(self.capacity),
+            });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        if requests > 0 {
+            bucket.requests.add(key, requests);
+        }
+        if bytes > 0 {
+            bucket.bytes.add(key, bytes);
+        }
+    }
+
+    /// the `n` most requested keys in the window
+    pub fn top_requests(&self, n: usize) -> Vec<HotKeyCount> {
+        self.top(n, |bucket| &bucket.requests, Instant::now())
+    }
+
+    /// the `n` keys with the most bytes transferred in the window
+    pub fn top_bytes(&self, n: usize) -> Vec<HotKeyCount> {
+        self.top(n, |bucket| &bucket.bytes, Instant::now())
+    }
+
+    fn top(&self, n: usize, sketch: impl Fn(&Bucket) -> &SpaceSaving, now: Instant) -> Vec<HotKeyCount> {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.expire(&mut buckets, self.bucket_index(now));
+        let mut merged: HashMap<HotKey, Counter> = HashMap::new();
+        for bucket in buckets.iter() {
+            for (key, counter) in sketch(bucket).counters.iter() {
+                let merged = merged.entry(*key).or_default();
+                merged.count += counter.count;
+                merged.error += counter.error;
+            }
+        }
+        drop(buckets);
+        let mut top: Vec<HotKeyCount> = merged.into_iter()
+            .map(|(key, counter)| HotKeyCount { key, count: counter.count, error: counter.error })
+            .collect();
+        top.sort_by_key(|count| std::cmp::Reverse(count.count));
+        top.truncate(n);
+        top
+    }
+
+    fn bucket_span(&self) -> Duration {
+        (self.window / BUCKETS).max(Duration::from_secs(1))
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.started).as_secs_f64() / self.bucket_span().as_secs_f64()) as u64
+    }
+
+    /// drop the buckets which slid out of the window
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, index: u64) {
+        while let Some(bucket) = buckets.front() {
+            if bucket.index + (BUCKETS as u64) <= index {
+                buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+impl CommandObserver for HotKeys {
+    fn on_command(&self, record: CommandRecord) {
+        let (file_type, guid, hash) = match (record.file_type, record.guid, record.hash) {
+            (Some(file_type), Some(guid), Some(hash)) => (file_type, guid, hash),
+            _ => return,
+        };
+        let key = HotKey { file_type, guid, hash };
+        match record.kind {
+            CommandKind::Get => self.add(key, 1, record.bytes),
+            CommandKind::Put => self.add(key, 0, record.bytes),
+            CommandKind::StartTransaction | CommandKind::EndTransaction => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HexString;
+
+    use super::*;
+
+    fn key(n: u8) -> HotKey {
+        HotKey { file_type: UnityFileType::Asset, guid: HexString([n; 16]), hash: HexString([n; 16]) }
+    }
+
+    fn counts(top: &[HotKeyCount]) -> Vec<(HotKey, u64, u64)> {
+        top.iter().map(|count| (count.key, count.count, count.error)).collect()
+    }
+
+    #[test]
+    fn top_keys_are_ordered_by_count() {
+        let hot_keys = HotKeys::new(10, Duration::from_secs(60));
+        for (n, requests, bytes) in [(1, 2, 10), (2, 5, 1), (3, 1, 500), (2, 0, 100)] {
+            for _ in 0..requests {
+                hot_keys.add(key(n), 1, 0);
+            }
+            hot_keys.add(key(n), 0, bytes);
+        }
+        assert_eq!(counts(&hot_keys.top_requests(2)), vec![(key(2), 5, 0), (key(1), 2, 0)]);
+        assert_eq!(counts(&hot_keys.top_bytes(10)), vec![(key(3), 500, 0), (key(2), 101, 0), (key(1), 10, 0)]);
+    }
+
+    #[test]
+    fn new_key_takes_over_the_smallest_counter() {
+        let mut sketch = SpaceSaving::new(2);
+        sketch.add(key(1), 3);
+        sketch.add(key(2), 1);
+        sketch.add(key(3), 1);
+        assert!(!sketch.counters.contains_key(&key(2)));
+        let counter = sketch.counters[&key(3)];
+        assert_eq!((counter.count, counter.error), (2, 1));
+        // the smallest is now key 3, with 2
+        sketch.add(key(4), 5);
+        assert!(!sketch.counters.contains_key(&key(3)));
+        let counter = sketch.counters[&key(4)];
+        assert_eq!((counter.count, counter.error), (7, 2));
+        assert_eq!(sketch.counters[&key(1)].count, 3);
+        assert_eq!(sketch.by_count.len(), sketch.counters.len());
+        assert_eq!(sketch.by_count.keys().map(|(count, _)| *count).collect::<Vec<_>>(), vec![3, 7]);
+    }
+
+    #[test]
+    fn frequent_keys_survive_many_unique_keys() {
+        let mut sketch = SpaceSaving::new(16);
+        for i in 0..10_000u32 {
+            if i % 10 == 0 {
+                sketch.add(key(0), 1);
+            }
+            let unique = HotKey { file_type: UnityFileType::Info, guid: HexString([(i % 251) as u8; 16]), hash: HexString([(i / 251) as u8; 16]) };
+            sketch.add(unique, 1);
+        }
+        assert_eq!(sketch.counters.len(), 16);
+        assert!(sketch.counters[&key(0)].count >= 1000);
+    }
+
+    #[test]
+    fn keys_expire_with_their_bucket() {
+        // six buckets of 10s
+        let hot_keys = HotKeys::new(10, Duration::from_secs(60));
+        let start = hot_keys.started;
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        hot_keys.add_at(key(1), 1, 0, at(5));
+        hot_keys.add_at(key(2), 1, 0, at(15));
+        hot_keys.add_at(key(2), 1, 0, at(25));
+        let top = |secs| counts(&hot_keys.top(10, |bucket| &bucket.requests, at(secs)));
+        assert_eq!(top(59), vec![(key(2), 2, 0), (key(1), 1, 0)]);
+        // the bucket of 0s to 10s slid out
+        assert_eq!(top(60), vec![(key(2), 2, 0)]);
+        assert_eq!(top(75), vec![(key(2), 1, 0)]);
+        assert_eq!(top(90), vec![]);
+    }
+}
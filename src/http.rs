@@ -0,0 +1,210 @@
+//! A minimal HTTP/1.1 server for the metrics and admin endpoints.
+//! One request per connection, no chunked bodies.
+
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::{debug, error};
+
+/// Max size of the request line and headers
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+/// Max size of a request body
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+/// Max time to receive a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// value of a `key=value` pair of the query string. values are not percent-decoded.
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        self.query.as_deref()?.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", body.into())
+    }
+
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "application/json", body.into())
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found\n")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::text(405, "method not allowed\n")
+    }
+}
+
+/// accept connections forever and answer each request with `handler`
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F)
+    where
+        F: Fn(Request) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output=Response> + Send,
+{
+    loop {
+        let (mut conn, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(error = %e, "accept http connection error");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = conn.split();
+            let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(reader)).await {
+                Ok(Ok(request)) => {
+                    debug!(%addr, method = %request.method, path = %request.path, "http request");
+                    handler(request).await
+                }
+                Ok(Err(reason)) => Response::text(400, format!("{}\n", reason)),
+                Err(_) => Response::text(408, "request timeout\n"),
+            };
+            let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                               response.status, reason_phrase(response.status), response.content_type, response.body.len());
+            let result = async {
+                writer.write_all(head.as_bytes()).await?;
+                writer.write_all(&response.body).await?;
+                writer.flush().await
+            }.await;
+            if let Err(e) = result {
+                debug!(%addr, error = %e, "write http response error");
+            }
+        });
+    }
+}
+
+async fn read_request<R: AsyncRead + Unpin>(reader: R) -> Result<Request, String> {
+    let mut reader = BufReader::new(reader).take(MAX_HEAD_SIZE);
+    let mut line = String::new();
+    reader.read_line(&mut line).await.map_err(|e| e.to_string())?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err("malformed request line".to_string()),
+    };
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Err("headers too long or incomplete".to_string());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<u64>().map_err(|_| "invalid content-length".to_string())?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err("body too large".to_string());
+    }
+    let mut body = vec![0u8; content_length as usize];
+    reader.into_inner().read_exact(&mut body).await.map_err(|e| e.to_string())?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target, None),
+    };
+    Ok(Request {
+        method,
+        path,
+        query,
+        body,
+    })
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(request: &str) -> Result<Request, String> {
+        read_request(request.as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn malformed_request_line() {
+        assert_eq!(read("GARBAGE\r\n\r\n").await.unwrap_err(), "malformed request line");
+        assert_eq!(read("").await.unwrap_err(), "malformed request line");
+    }
+
+    #[tokio::test]
+    async fn headers_over_the_limit() {
+        let request = format!("GET /stats HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE as usize));
+        assert_eq!(read(&request).await.unwrap_err(), "headers too long or incomplete");
+        assert_eq!(read("GET /stats HTTP/1.1\r\nHost: x\r\n").await.unwrap_err(), "headers too long or incomplete");
+    }
+
+    #[tokio::test]
+    async fn content_length() {
+        let request = format!("POST /mode HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        assert_eq!(read(&request).await.unwrap_err(), "body too large");
+        assert_eq!(read("POST /mode HTTP/1.1\r\nContent-Length: ten\r\n\r\n").await.unwrap_err(), "invalid content-length");
+        let request = read("POST /mode HTTP/1.1\r\ncontent-length: 9\r\n\r\nread-only").await.unwrap();
+        assert_eq!(request.body, b"read-only");
+    }
+
+    #[tokio::test]
+    async fn query_is_split_from_the_path() {
+        let request = read("POST /cleanup?older_than=2h&dry HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/cleanup");
+        assert_eq!(request.query.as_deref(), Some("older_than=2h&dry"));
+        assert_eq!(request.query_param("older_than"), Some("2h"));
+        assert_eq!(request.query_param("dry"), None);
+        assert!(request.body.is_empty());
+
+        let request = read("GET /stats HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(request.path, "/stats");
+        assert_eq!(request.query, None);
+        assert_eq!(request.query_param("older_than"), None);
+    }
+}
@@ -2,10 +2,27 @@ use std::fmt::{Debug, Display, Formatter, Write};
 use std::num::ParseIntError;
 use std::str::Utf8Error;
 
-pub use serve::{handle, Handler};
+pub use serve::{CommandKind, CommandObserver, CommandOutcome, CommandRecord, handle, handle_with_options, HandleOptions, Handler, StorageUsage};
 
 mod serve;
+pub mod access_log;
+pub mod admin;
+pub mod client;
+pub mod config;
+pub mod connections;
 pub mod handlers;
+pub mod hot_keys;
+pub mod http;
+pub mod limits;
+pub mod mode;
+pub mod listener;
+pub mod metrics;
+pub mod protocol;
+pub mod proxy_protocol;
+pub mod recorder;
+pub mod report;
+pub mod shutdown;
+pub mod stats;
 
 // region Error
 
@@ -18,11 +35,15 @@ pub enum Error {
     UnknownTransactionCommand(u8),
     UnknownPushCommand(u8),
     UnknownCommand(u8),
+    UnknownResponse(u8),
+    ResponseNotMatched,
     FileTooLarge {
         max_size: usize,
         size: usize,
     },
     NotInTransaction,
+    Timeout(&'static str),
+    InvalidProxyHeader(String),
     Utf8Error(Utf8Error),
     ParseIntError(ParseIntError),
     IoError(std::io::Error),
@@ -63,6 +84,40 @@ impl From<()> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// A recoverable error only rejects the current command.
+    /// The unread payload of the command is drained and the session continues.
+    /// Other errors mean the stream is broken or out of sync, so the session ends.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Error::FileTooLarge { .. } | Error::NotInTransaction | Error::HandlerError(_))
+    }
+
+    /// name of the variant, for metrics and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::ReadVersionError => "ReadVersionError",
+            Error::WrongVersion(_) => "WrongVersion",
+            Error::UnknownFileTypeByte(_) => "UnknownFileTypeByte",
+            Error::UnknownFileTypeExt(_) => "UnknownFileTypeExt",
+            Error::UnknownTransactionCommand(_) => "UnknownTransactionCommand",
+            Error::UnknownPushCommand(_) => "UnknownPushCommand",
+            Error::UnknownCommand(_) => "UnknownCommand",
+            Error::UnknownResponse(_) => "UnknownResponse",
+            Error::ResponseNotMatched => "ResponseNotMatched",
+            Error::FileTooLarge { .. } => "FileTooLarge",
+            Error::NotInTransaction => "NotInTransaction",
+            Error::Timeout(_) => "Timeout",
+            Error::InvalidProxyHeader(_) => "InvalidProxyHeader",
+            Error::Utf8Error(_) => "Utf8Error",
+            Error::ParseIntError(_) => "ParseIntError",
+            Error::IoError(_) => "IoError",
+            Error::DecodeHexError(_) => "DecodeHexError",
+            Error::HandlerError(_) => "HandlerError",
+            Error::UnknownError => "UnknownError",
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -73,8 +128,12 @@ impl Display for Error {
             Error::UnknownTransactionCommand(e) => write!(f, "unknown transaction command: {:?}", e),
             Error::UnknownPushCommand(e) => write!(f, "unknown push command: {:?}", e),
             Error::UnknownCommand(e) => write!(f, "unknown command: {:?}", e),
+            Error::UnknownResponse(e) => write!(f, "unknown response: {:?}", e),
+            Error::ResponseNotMatched => write!(f, "response not matched"),
             Error::FileTooLarge { max_size, size } => write!(f, "file too large: {}. max size: {}. size", size, max_size),
             Error::NotInTransaction => write!(f, "not in transaction"),
+            Error::Timeout(e) => write!(f, "timeout: {}", e),
+            Error::InvalidProxyHeader(e) => write!(f, "invalid proxy protocol header: {}", e),
             Error::Utf8Error(e) => write!(f, "utf8 error: {:?}", e),
             Error::ParseIntError(e) => write!(f, "parse int error: {:?}", e),
             Error::IoError(e) => write!(f, "io error: {:?}", e),
@@ -107,6 +166,7 @@ impl UnityFileType {
         }
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn try_from_u8(b: u8) -> std::result::Result<Self, ()> {
         Ok(match b {
             0 => UnityFileType::Asset,
@@ -124,6 +184,7 @@ impl UnityFileType {
         }
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn try_from_ext(s: &str) -> std::result::Result<Self, ()> {
         match s {
             "bin" => Ok(UnityFileType::Asset),
@@ -141,6 +202,7 @@ impl UnityFileType {
         }
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn try_from_ext_char(b: u8) -> std::result::Result<Self, ()> {
         match b {
             b'a' => Ok(UnityFileType::Asset),
@@ -170,15 +232,21 @@ impl<const N: usize> HexString<N> {
     }
 }
 
+impl<const N: usize> Default for HexString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const N: usize> AsRef<[u8]> for HexString<N> {
     fn as_ref(&self) -> &[u8] {
         &self.0
     }
 }
 
-impl<const N: usize> ToString for HexString<N> {
-    fn to_string(&self) -> String {
-        self.to_hex_string()
+impl<const N: usize> Display for HexString<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex_string())
     }
 }
 
@@ -238,7 +306,7 @@ pub fn u32_to_be_hex_string(n: u32) -> String {
         ((n >> 24) & 0xff) as u8,
         ((n >> 16) & 0xff) as u8,
         ((n >> 8) & 0xff) as u8,
-        ((n >> 0) & 0xff) as u8,
+        (n & 0xff) as u8,
     ];
     encode_hex(&arr[..])
 }
\ No newline at end of file
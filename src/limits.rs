@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Notify;
+
+/// What to do with a connection over the limit
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitPolicy {
+    /// wait until another connection closes.
+    /// a connection waiting for its ip takes a place of the total limit,
+    /// and no more connections than the per ip limit may wait for one ip.
+    Queue,
+    /// close the connection
+    Reject,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitExceeded {
+    Total(usize),
+    PerIp(IpAddr, usize),
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::Total(max) => write!(f, "too many connections. max: {}", max),
+            LimitExceeded::PerIp(ip, max) => write!(f, "too many connections from {}. max: {}", ip, max),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[derive(Debug)]
+struct State {
+    /// Max number of concurrent connections
+    /// 0 for no limit
+    max_connections: usize,
+    /// Max number of concurrent connections from one client ip
+    /// 0 for no limit
+    max_connections_per_ip: usize,
+    policy: LimitPolicy,
+    /// connections holding a permit, including the ones waiting for their ip
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    waiting_per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    state: Mutex<State>,
+    released: Notify,
+    rejected_total: AtomicU64,
+    rejected_per_ip: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, max_connections_per_ip: usize, policy: LimitPolicy) -> Self {
+        Self {
+            state: Mutex::new(State {
+                max_connections,
+                max_connections_per_ip,
+                policy,
+                total: 0,
+                per_ip: HashMap::new(),
+                waiting_per_ip: HashMap::new(),
+            }),
+            released: Notify::new(),
+            rejected_total: AtomicU64::new(0),
+            rejected_per_ip: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.state.lock().unwrap().max_connections
+    }
+
+    pub fn max_connections_per_ip(&self) -> usize {
+        self.state.lock().unwrap().max_connections_per_ip
+    }
+
+    pub fn policy(&self) -> LimitPolicy {
+        self.state.lock().unwrap().policy
+    }
+
+    /// change the limits at runtime. open connections are kept even if they are over the new limits.
+    pub fn set_limits(&self, max_connections: usize, max_connections_per_ip: usize, policy: LimitPolicy) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.max_connections = max_connections;
+            state.max_connections_per_ip = max_connections_per_ip;
+            state.policy = policy;
+        }
+        // queued connections may fit in the new limits, or must be rejected by the new policy
+        self.released.notify_waiters();
+    }
+
+    /// number of connections holding a permit, including the ones waiting for their ip
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().total
+    }
+
+    /// number of connections rejected by the total limit
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected_total.load(Ordering::Relaxed)
+    }
+
+    /// number of connections rejected by the per ip limit
+    pub fn rejected_per_ip(&self) -> u64 {
+        self.rejected_per_ip.load(Ordering::Relaxed)
+    }
+
+    /// number of connections which had to wait for a permit
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// wait for a place in the total limit.
+    /// the accept loop waits here with the queue policy, so waiting clients stay in the listen backlog.
+    /// the ip is given to the permit with [`ConnectionPermit::set_ip`] once it is known.
+    pub async fn acquire(self: &Arc<Self>) -> ConnectionPermit {
+        let mut queued = false;
+        loop {
+            let released = self.released.notified();
+            if self.take_total().is_ok() {
+                return ConnectionPermit {
+                    limiter: self.clone(),
+                    ip: None,
+                };
+            }
+            if !queued {
+                queued = true;
+                self.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            released.await;
+        }
+    }
+
+    /// take a place in the total limit if there is one
+    pub fn try_acquire(self: &Arc<Self>) -> Result<ConnectionPermit, LimitExceeded> {
+        match self.take_total() {
+            Ok(()) => Ok(ConnectionPermit {
+                limiter: self.clone(),
+                ip: None,
+            }),
+            Err(e) => {
+                self.rejected_total.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    fn take_total(&self) -> Result<(), LimitExceeded> {
+        let mut state = self.state.lock().unwrap();
+        if state.max_connections != 0 && state.total >= state.max_connections {
+            return Err(LimitExceeded::Total(state.max_connections));
+        }
+        state.total += 1;
+        Ok(())
+    }
+
+    fn take_ip(&self, ip: IpAddr) -> Result<(), LimitExceeded> {
+        let mut state = self.state.lock().unwrap();
+        let max_connections_per_ip = state.max_connections_per_ip;
+        let count = state.per_ip.entry(ip).or_default();
+        if max_connections_per_ip != 0 && *count >= max_connections_per_ip {
+            return Err(LimitExceeded::PerIp(ip, max_connections_per_ip));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// join the queue of an ip. false if the queue is full.
+    fn join_ip_queue(&self, ip: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let max_waiting = state.max_connections_per_ip;
+        let waiting = state.waiting_per_ip.entry(ip).or_default();
+        if *waiting >= max_waiting {
+            return false;
+        }
+        *waiting += 1;
+        true
+    }
+
+    fn leave_ip_queue(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        decrement(&mut state.waiting_per_ip, ip);
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.total -= 1;
+            if let Some(ip) = ip {
+                decrement(&mut state.per_ip, ip);
+            }
+        }
+        self.released.notify_waiters();
+    }
+}
+
+fn decrement(counts: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+    if let Some(count) = counts.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&ip);
+        }
+    }
+}
+
+/// A place in the total limit, and in the limit of the client ip once it is set.
+/// The places are given back when dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionPermit {
+    /// count the connection against the limit of its client ip.
+    /// connections without an ip only count against the total limit.
+    /// with the queue policy, wait until another connection from the ip closes.
+    /// the connection is rejected if as many connections are already waiting for the ip.
+    pub async fn set_ip(&mut self, ip: Option<IpAddr>) -> Result<(), LimitExceeded> {
+        let ip = match ip {
+            Some(ip) if self.ip.is_none() => ip,
+            _ => return Ok(()),
+        };
+        let limiter = &self.limiter;
+        let mut waiting = None;
+        loop {
+            let released = limiter.released.notified();
+            match limiter.take_ip(ip) {
+                Ok(()) => {
+                    self.ip = Some(ip);
+                    return Ok(());
+                }
+                Err(e) => {
+                    if waiting.is_none() {
+                        if limiter.policy() == LimitPolicy::Reject || !limiter.join_ip_queue(ip) {
+                            limiter.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+                            return Err(e);
+                        }
+                        waiting = Some(IpQueueEntry { limiter, ip });
+                        limiter.queued.fetch_add(1, Ordering::Relaxed);
+                    } else if limiter.policy() == LimitPolicy::Reject {
+                        limiter.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                    released.await;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+/// leaves the queue of an ip when dropped, also if the wait is cancelled
+struct IpQueueEntry<'a> {
+    limiter: &'a ConnectionLimiter,
+    ip: IpAddr,
+}
+
+impl Drop for IpQueueEntry<'_> {
+    fn drop(&mut self) {
+        self.limiter.leave_ip_queue(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    /// true if the future is still waiting after a while
+    async fn waits<F: std::future::Future>(future: F) -> bool {
+        timeout(Duration::from_millis(50), future).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn total_limit_rejects() {
+        let limiter = Arc::new(ConnectionLimiter::new(2, 0, LimitPolicy::Reject));
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert_eq!(limiter.try_acquire().unwrap_err(), LimitExceeded::Total(2));
+        assert_eq!(limiter.rejected_total(), 1);
+        drop(first);
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[tokio::test]
+    async fn total_limit_queues() {
+        let limiter = Arc::new(ConnectionLimiter::new(1, 0, LimitPolicy::Queue));
+        let first = limiter.acquire().await;
+        let waiting = limiter.clone();
+        let second = tokio::spawn(async move { waiting.acquire().await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!second.is_finished());
+        assert_eq!(limiter.queued(), 1);
+        assert_eq!(limiter.active(), 1);
+        drop(first);
+        let _second = second.await.unwrap();
+        assert_eq!(limiter.active(), 1);
+        assert_eq!(limiter.rejected_total(), 0);
+    }
+
+    #[tokio::test]
+    async fn per_ip_limit_rejects() {
+        let limiter = Arc::new(ConnectionLimiter::new(0, 1, LimitPolicy::Reject));
+        let mut first = limiter.try_acquire().unwrap();
+        first.set_ip(Some(IP)).await.unwrap();
+        let mut second = limiter.try_acquire().unwrap();
+        assert_eq!(second.set_ip(Some(IP)).await.unwrap_err(), LimitExceeded::PerIp(IP, 1));
+        let mut other = limiter.try_acquire().unwrap();
+        other.set_ip(Some(OTHER_IP)).await.unwrap();
+        // connections without an ip only count against the total limit
+        let mut unix = limiter.try_acquire().unwrap();
+        unix.set_ip(None).await.unwrap();
+        assert_eq!(limiter.rejected_per_ip(), 1);
+        drop(first);
+        second.set_ip(Some(IP)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn per_ip_queue_takes_a_place_in_the_total_limit_and_is_capped() {
+        let limiter = Arc::new(ConnectionLimiter::new(4, 1, LimitPolicy::Queue));
+        let mut first = limiter.acquire().await;
+        first.set_ip(Some(IP)).await.unwrap();
+
+        let mut second = limiter.acquire().await;
+        let (sender, mut receiver) = tokio::sync::oneshot::channel();
+        let waiter = tokio::spawn(async move {
+            let result = second.set_ip(Some(IP)).await;
+            let _ = sender.send(());
+            result.map(|_| second)
+        });
+        assert!(waits(&mut receiver).await);
+        assert_eq!(limiter.active(), 2);
+
+        // the queue of the ip is full
+        let mut third = limiter.acquire().await;
+        assert_eq!(third.set_ip(Some(IP)).await.unwrap_err(), LimitExceeded::PerIp(IP, 1));
+        drop(third);
+        assert_eq!(limiter.rejected_per_ip(), 1);
+
+        drop(first);
+        let _second = waiter.await.unwrap().unwrap();
+        assert_eq!(limiter.active(), 1);
+        assert_eq!(limiter.queued(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_wait_leaves_the_ip_queue() {
+        let limiter = Arc::new(ConnectionLimiter::new(0, 1, LimitPolicy::Queue));
+        let mut first = limiter.acquire().await;
+        first.set_ip(Some(IP)).await.unwrap();
+        let mut second = limiter.acquire().await;
+        assert!(waits(second.set_ip(Some(IP))).await);
+        drop(second);
+        let mut third = limiter.acquire().await;
+        assert!(waits(third.set_ip(Some(IP))).await);
+    }
+
+    #[tokio::test]
+    async fn queued_connection_is_rejected_after_switching_to_reject() {
+        let limiter = Arc::new(ConnectionLimiter::new(0, 1, LimitPolicy::Queue));
+        let mut first = limiter.acquire().await;
+        first.set_ip(Some(IP)).await.unwrap();
+        let mut second = limiter.acquire().await;
+        let waiter = tokio::spawn(async move { second.set_ip(Some(IP)).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        limiter.set_limits(0, 1, LimitPolicy::Reject);
+        assert_eq!(waiter.await.unwrap().unwrap_err(), LimitExceeded::PerIp(IP, 1));
+        assert_eq!(limiter.active(), 1);
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Where to accept connections
+/// - `0.0.0.0:8126`: tcp
+/// - `unix:/run/unity-cache-server.sock`: unix domain socket
+/// - `systemd`: all sockets passed by systemd socket activation
+/// - `systemd:name`: the sockets passed by systemd with `FileDescriptorName=name`
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd(Option<String>),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err("unix sockets are not supported on this platform".to_string());
+            }
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        if s == "systemd" {
+            return Ok(ListenAddr::Systemd(None));
+        }
+        if let Some(name) = s.strip_prefix("systemd:") {
+            if name.is_empty() {
+                return Err("systemd socket name is empty".to_string());
+            }
+            return Ok(ListenAddr::Systemd(Some(name.to_string())));
+        }
+        s.parse().map(ListenAddr::Tcp)
+            .map_err(|_| "expected ip:port, unix:PATH, systemd or systemd:NAME".to_string())
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Systemd(None) => write!(f, "systemd"),
+            ListenAddr::Systemd(Some(name)) => write!(f, "systemd:{}", name),
+        }
+    }
+}
+
+/// Address of the client
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// unix socket clients are usually unnamed
+    Unix(Option<PathBuf>),
+}
+
+impl PeerAddr {
+    /// None for unix sockets
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// bind a tcp or unix address. systemd addresses are taken from [`systemd::take_sockets`].
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                // a socket file left by a previous run would make bind fail.
+                // it is only removed if nothing listens on it anymore.
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        match UnixStream::connect(path).await {
+                            Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is used by a running server", path.display()))),
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+                            Err(_) => {}
+                        }
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?, Some(path.clone())))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't bind {}", addr))),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Connection, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Connection::Tcp(conn), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Connection::Unix(conn), PeerAddr::Unix(addr.as_pathname().map(PathBuf::from))))
+            }
+        }
+    }
+
+    /// the bound address, for logging
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => match listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(PathBuf::from)) {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix".to_string(),
+            },
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // remove the socket file created by bind. inherited sockets are owned by systemd.
+        #[cfg(unix)]
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// An accepted connection
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// disable Nagle's algorithm on tcp. no-op for unix sockets.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Connection::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_shutdown(cx),
+        }
+    }
+}
+
+/// systemd socket activation
+#[cfg(unix)]
+pub mod systemd {
+    use std::io;
+    use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+    use super::Listener;
+
+    /// first file descriptor passed by systemd
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// A listening socket passed by systemd, not yet registered with the tokio runtime
+    #[derive(Debug)]
+    pub enum Socket {
+        Tcp(std::net::TcpListener),
+        Unix(std::os::unix::net::UnixListener),
+    }
+
+    impl Socket {
+        /// must be called inside the tokio runtime
+        pub fn into_listener(self) -> io::Result<Listener> {
+            match self {
+                Socket::Tcp(listener) => Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?)),
+                Socket::Unix(listener) => Ok(Listener::Unix(tokio::net::UnixListener::from_std(listener)?, None)),
+            }
+        }
+    }
+
+    /// take the listening sockets passed by systemd, with their `FileDescriptorName`.
+    /// the environment variables are removed so the sockets are taken only once.
+    /// changing the environment is unsound while other threads may read it,
+    /// so this must be called before the tokio runtime or any other thread is started.
+    pub fn take_sockets() -> io::Result<Vec<(Option<String>, Socket)>> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let fds = std::env::var("LISTEN_FDS").ok();
+        let names = std::env::var("LISTEN_FDNAMES").ok();
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+
+        let (pid, fds) = match (pid, fds) {
+            (Some(pid), Some(fds)) => (pid, fds),
+            _ => return Ok(Vec::new()),
+        };
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            // the sockets are meant for another process
+            return Ok(Vec::new());
+        }
+        let count: RawFd = fds.parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid LISTEN_FDS {:?}", fds)))?;
+        let names: Vec<&str> = names.as_deref().map(|names| names.split(':').collect()).unwrap_or_default();
+
+        let mut sockets = Vec::new();
+        for i in 0..count {
+            let fd = LISTEN_FDS_START + i;
+            let name = names.get(i as usize).map(|name| name.to_string());
+            sockets.push((name, from_raw_fd(fd)?));
+        }
+        Ok(sockets)
+    }
+
+    fn from_raw_fd(fd: RawFd) -> io::Result<Socket> {
+        // SAFETY: systemd passes the sockets as fds from 3 to 3 + LISTEN_FDS. nothing else owns them.
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        // local_addr fails if the socket is not a unix socket
+        if listener.local_addr().is_ok() {
+            listener.set_nonblocking(true)?;
+            return Ok(Socket::Unix(listener));
+        }
+        let fd = listener.into_raw_fd();
+        // SAFETY: the fd was released by the unix listener above
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.local_addr()
+            .map_err(|e| io::Error::new(e.kind(), format!("fd {} from systemd is not a tcp or unix listening socket: {}", fd, e)))?;
+        listener.set_nonblocking(true)?;
+        Ok(Socket::Tcp(listener))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("ucs-listener-test-{}.sock", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn socket_file_of_a_running_server_is_kept() {
+        let path = socket_path();
+        let addr = ListenAddr::Unix(path.clone());
+        let running = Listener::bind(&addr).await.unwrap();
+        let error = Listener::bind(&addr).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+        drop(running);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stale_socket_file_is_replaced() {
+        let path = socket_path();
+        let addr = ListenAddr::Unix(path.clone());
+        // the file stays when the listener is closed
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&addr).await.unwrap();
+        assert!(UnixStream::connect(&path).await.is_ok());
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn other_files_are_not_removed() {
+        let path = socket_path();
+        std::fs::write(&path, b"data").unwrap();
+        assert!(Listener::bind(&ListenAddr::Unix(path.clone())).await.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,43 +1,556 @@
+use std::io::IsTerminal;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use tokio::io::BufReader;
+use anyhow::Context;
+use clap::Parser;
+use tokio::io::{AsyncWriteExt, BufReader};
 use tokio::io::BufWriter;
-use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 use tokio::time::sleep;
+use tracing::{error, info, info_span, Instrument, warn};
+use tracing::field;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt, reload};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-use unity_cache_server::handle;
-use unity_cache_server::handlers::FileSystemHandler;
+use unity_cache_server::{Error, Handler, handle_with_options, HandleOptions, metrics, proxy_protocol};
+use unity_cache_server::access_log::AccessLog;
+use unity_cache_server::admin::{Admin, AdminListener};
+use unity_cache_server::config::{Args, Config, ListenerSettings, LogFormat};
+use unity_cache_server::connections::{Connections, CountingStream};
+use unity_cache_server::handlers::BoxedHandler;
+use unity_cache_server::hot_keys::{HotKeyCount, HotKeys};
+use unity_cache_server::http::{self, Request, Response};
+use unity_cache_server::limits::{ConnectionLimiter, ConnectionPermit, LimitExceeded, LimitPolicy};
+use unity_cache_server::listener::{Connection, ListenAddr, Listener, PeerAddr};
+#[cfg(unix)]
+use unity_cache_server::listener::systemd;
+use unity_cache_server::metrics::ListenerMetrics;
+use unity_cache_server::mode::ModeSwitch;
+use unity_cache_server::recorder::{self, Recorder, RecordingReader};
+use unity_cache_server::shutdown::{Shutdown, ShutdownSignal, ShutdownState};
+use unity_cache_server::stats::Stats;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8126").await?;
-    let mut fs_handler = FileSystemHandler::new(PathBuf::from(".cache_fs"), PathBuf::from(".cache_fs"));
-    fs_handler.set_max_file_size(256 * 1024 * 1024);
+/// Time for sessions to cancel their transactions after the grace period
+const SHUTDOWN_TERMINATE_PERIOD: Duration = Duration::from_secs(5);
 
+fn main() -> anyhow::Result<()> {
+    // before the runtime starts its threads. taking the sockets changes the environment.
+    #[cfg(unix)]
+    let sockets = systemd::take_sockets().context("take sockets from systemd failed")?;
+    let args = Args::parse();
+    let config = Config::load(&args)?;
+    let (filter, log_level) = reload::Layer::new(LevelFilter::from(config.log.level));
+    let output = match config.log.format {
+        LogFormat::Text => fmt::layer().with_ansi(std::io::stdout().is_terminal()).boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .init();
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(async {
+        #[cfg(unix)]
+        let inherited = sockets.into_iter()
+            .map(|(name, socket)| Ok((name, socket.into_listener()?)))
+            .collect::<std::io::Result<Vec<_>>>()
+            .context("take sockets from systemd failed")?;
+        #[cfg(not(unix))]
+        let inherited = Vec::new();
+        run(config, args, log_level, inherited).await
+    })
+}
+
+/// handle to change the log level at runtime
+type LogLevelHandle = reload::Handle<LevelFilter, tracing_subscriber::Registry>;
+
+/// state shared by the accept loops of one listener
+struct Server {
+    name: String,
+    /// replaced by a reload. connections take a copy when they start.
+    settings: RwLock<ListenerSettings>,
+    handler: BoxedHandler,
+    /// replaced by a reload, like `settings`
+    options: RwLock<HandleOptions>,
+    limiter: Arc<ConnectionLimiter>,
+    /// Directory to record the inbound byte stream of every connection into
+    record_dir: Option<PathBuf>,
+    /// shared by all listeners
+    connection_id: Arc<AtomicU64>,
+    /// shared by all listeners
+    access_log: Option<AccessLog>,
+    /// observes the sessions through `options`
+    stats: Arc<Stats>,
+    /// shared by all listeners
+    connections: Arc<Connections>,
+    /// observes the sessions through `options`. None if the tracking is disabled.
+    hot_keys: Option<Arc<HotKeys>>,
+}
+
+/// `inherited` are the sockets passed by systemd, with their names
+async fn run(config: Config, args: Args, log_level: LogLevelHandle, mut inherited: Vec<(Option<String>, Listener)>) -> anyhow::Result<()> {
+    let shutdown = Shutdown::new();
+    let mode = ModeSwitch::new(config.server.mode);
+    #[cfg(unix)]
+    tokio::spawn(switch_mode_on_signal(mode.clone()));
+    let connection_id = Arc::new(AtomicU64::new(0));
+    let registry = Arc::new(Connections::new());
+    let access_log = match &config.access_log.path {
+        Some(path) => Some(AccessLog::open(path, config.access_log.rotation()).await
+            .with_context(|| format!("open access log {} failed", path.display()))?),
+        None => None,
+    };
+
+    let mut servers = Vec::new();
+    let mut listeners = Vec::new();
+    for settings in config.listeners() {
+        let stats = Arc::new(Stats::new());
+        let mut options = config.handle_options(&settings);
+        options.set_shutdown(Some(shutdown.signal()));
+        options.set_mode(mode.clone());
+        options.add_observer(stats.clone());
+        let hot_keys = match config.hot_keys.capacity {
+            0 => None,
+            capacity => Some(Arc::new(HotKeys::new(capacity, config.hot_keys.window.to_duration()))),
+        };
+        if let Some(hot_keys) = &hot_keys {
+            options.add_observer(hot_keys.clone());
+        }
+        let server = Arc::new(Server {
+            name: settings.name.clone(),
+            settings: RwLock::new(settings.clone()),
+            handler: settings.storage.create_handler(),
+            options: RwLock::new(options),
+            limiter: Arc::new(settings.limits.connection_limiter()),
+            record_dir: config.server.record_dir.clone(),
+            connection_id: connection_id.clone(),
+            access_log: access_log.clone(),
+            stats,
+            connections: registry.clone(),
+            hot_keys,
+        });
+        for addr in settings.listen_addrs() {
+            let bound = match &addr {
+                ListenAddr::Systemd(name) => take_systemd_listeners(&mut inherited, name.as_deref())
+                    .with_context(|| format!("listener {:?}: listen on {} failed", settings.name, addr))?,
+                _ => vec![Listener::bind(&addr).await
+                    .with_context(|| format!("listener {:?}: listen on {} failed", settings.name, addr))?],
+            };
+            for listener in bound {
+                info!(listener = %settings.name, addr = %listener.local_addr(), backend = %settings.storage.backend, "listening");
+                listeners.push((listener, server.clone()));
+            }
+        }
+        servers.push(server);
+    }
+
+    // every accept loop and connection task holds a sender. recv() returns None once all of them are done.
+    let (connections, mut connections_done) = mpsc::channel::<()>(1);
+    for (listener, server) in listeners {
+        tokio::spawn(accept_loop(listener, server, connections.clone(), shutdown.signal()));
+    }
+    drop(connections);
+    for (name, listener) in inherited {
+        warn!(addr = %listener.local_addr(), name = name.as_deref().unwrap_or_default(), "socket from systemd is not used by any listener");
+    }
+    let stats_interval = config.log.stats_interval.to_option();
+    if config.metrics.listen.is_some() || config.admin.listen.is_some() || stats_interval.is_some() {
+        let interval = config.metrics.storage_usage_interval.to_duration();
+        for server in servers.iter() {
+            tokio::spawn(measure_storage_usage(server.clone(), interval));
+        }
+    }
+    if let Some(interval) = stats_interval {
+        tokio::spawn(log_stats_periodically(servers.clone(), interval));
+    }
+    #[cfg(unix)]
+    tokio::spawn(dump_on_signal(servers.clone(), registry.clone()));
+    if let Some(interval) = config.hot_keys.log_interval.to_option() {
+        tokio::spawn(log_hot_keys(servers.clone(), interval, config.hot_keys.top));
+    }
+    if let Some(addr) = config.metrics.listen {
+        let listener = tokio::net::TcpListener::bind(addr).await
+            .with_context(|| format!("metrics: listen on {} failed", addr))?;
+        info!(%addr, "serving metrics");
+        let servers = servers.clone();
+        tokio::spawn(http::serve(listener, move |request| {
+            let servers = servers.clone();
+            async move { metrics_response(&servers, request) }
+        }));
+    }
+    if let Some(addr) = config.admin.listen {
+        let listener = tokio::net::TcpListener::bind(addr).await
+            .with_context(|| format!("admin: listen on {} failed", addr))?;
+        info!(%addr, "serving admin api");
+        let admin_listeners = servers.iter().map(|server| AdminListener {
+            name: server.name.clone(),
+            backend: server.settings.read().unwrap().storage.backend.to_string(),
+            handler: server.handler.clone(),
+            stats: server.stats.clone(),
+            hot_keys: server.hot_keys.clone(),
+        }).collect();
+        let admin = Admin::new(admin_listeners, registry, mode.clone());
+        tokio::spawn(http::serve(listener, move |request| {
+            let admin = admin.clone();
+            async move { admin.handle(request).await }
+        }));
+    }
+    let grace_period = config.server.shutdown_grace_period.to_duration();
+    #[cfg(unix)]
+    tokio::spawn(reload_on_signal(args, config, log_level, servers.clone()));
+    #[cfg(not(unix))]
+    drop((args, config, log_level));
+
+    shutdown_signal().await;
+    info!(?grace_period, "shutting down. waiting for open transactions");
+    shutdown.drain();
+    if tokio::time::timeout(grace_period, connections_done.recv()).await.is_err() {
+        warn!("grace period is over. cancel open transactions");
+        shutdown.terminate();
+        if tokio::time::timeout(SHUTDOWN_TERMINATE_PERIOD, connections_done.recv()).await.is_err() {
+            warn!("some connections did not close in time");
+        }
+    }
+    log_stats(&servers);
+    if let Some(access_log) = &access_log {
+        access_log.flush().await;
+    }
+    info!("shutdown complete");
+    Ok(())
+}
+
+/// take the inherited sockets with the given name, or all of them
+fn take_systemd_listeners(inherited: &mut Vec<(Option<String>, Listener)>, name: Option<&str>) -> anyhow::Result<Vec<Listener>> {
+    let (taken, rest) = std::mem::take(inherited).into_iter()
+        .partition::<Vec<_>, _>(|(socket_name, _)| name.is_none() || socket_name.as_deref() == name);
+    *inherited = rest;
+    if taken.is_empty() {
+        match name {
+            None => anyhow::bail!("no socket is passed by systemd"),
+            Some(name) => anyhow::bail!("no socket named {:?} is passed by systemd. set FileDescriptorName= in the socket unit", name),
+        }
+    }
+    Ok(taken.into_iter().map(|(_, listener)| listener).collect())
+}
+
+/// accept connections until the server starts draining
+async fn accept_loop(listener: Listener, server: Arc<Server>, connections: mpsc::Sender<()>, shutdown: ShutdownSignal) {
+    let limiter = &server.limiter;
     loop {
-        match listener.accept().await {
-            Ok((mut conn, addr)) => {
-                println!("Accept connection from {}", addr);
-                let handler = fs_handler.clone();
-                tokio::spawn(async move {
-                    let (reader, writer) = conn.split();
-                    let mut reader = BufReader::new(reader);
-                    let mut writer = BufWriter::new(writer);
-                    match handle(&mut reader, &mut writer, handler).await {
-                        Ok(_) => {
-                            println!("Client {} quit", addr);
-                        }
-                        Err(e) => {
-                            println!("Client {} disconnect with error: {:?}", addr, e);
-                        }
-                    }
-                });
+        // with the queue policy, clients wait in the listen backlog until a connection closes
+        let mut permit = None;
+        if limiter.policy() == LimitPolicy::Queue {
+            tokio::select! {
+                acquired = limiter.acquire() => permit = Some(acquired),
+                _ = shutdown.wait(ShutdownState::Draining) => break,
+            }
+        }
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait(ShutdownState::Draining) => break,
+        };
+        match accepted {
+            Ok((conn, addr)) => {
+                let permit = match permit {
+                    Some(permit) => Ok(permit),
+                    None => limiter.try_acquire(),
+                };
+                let connection_id = server.connection_id.fetch_add(1, Ordering::Relaxed) + 1;
+                let span = info_span!("connection", id = connection_id, listener = %server.name, peer = %addr, client = field::Empty);
+                tokio::spawn(serve_connection(conn, addr, permit, connection_id, server.clone(), connections.clone(), shutdown.clone()).instrument(span));
             }
             Err(e) => {
-                println!("Accept connection error: {:?}", e);
+                error!(error = %e, "accept connection error");
                 sleep(Duration::from_secs(1)).await;
             }
         }
     }
 }
+
+/// close a connection over the connection limits
+async fn reject_connection(conn: &mut Connection, server: &Server, reason: LimitExceeded) {
+    let limiter = &server.limiter;
+    server.stats.connection_rejected();
+    warn!(%reason, rejected_total = limiter.rejected_total(), rejected_per_ip = limiter.rejected_per_ip(), "reject connection");
+    let _ = conn.shutdown().await;
+}
+
+/// serve a connection inside its span. the span has the connection id and the peer address.
+async fn serve_connection(mut conn: Connection, addr: PeerAddr, permit: Result<ConnectionPermit, LimitExceeded>, connection_id: u64, server: Arc<Server>, connection: mpsc::Sender<()>, shutdown: ShutdownSignal) {
+    server.stats.connection_accepted();
+    let mut permit = match permit {
+        Ok(permit) => permit,
+        Err(e) => {
+            reject_connection(&mut conn, &server, e).await;
+            return;
+        }
+    };
+    if let Err(e) = conn.set_nodelay(true) {
+        warn!(error = %e, "set nodelay error");
+    }
+    let settings = server.settings.read().unwrap().clone();
+    let mut options = server.options.read().unwrap().clone();
+    let addr = if settings.is_trusted_proxy(addr.ip()) {
+        let header = proxy_protocol::read_header(&mut conn);
+        let header = match options.handshake_timeout() {
+            Some(timeout) => tokio::time::timeout(timeout, header).await.unwrap_or(Err(Error::Timeout("proxy header"))),
+            None => header.await,
+        };
+        match header {
+            Ok(Some(client)) => {
+                tracing::Span::current().record("client", field::display(client));
+                info!("accept proxied connection");
+                PeerAddr::Tcp(client)
+            }
+            Ok(None) => {
+                info!("accept connection. proxy header has no client address");
+                addr
+            }
+            Err(e) => {
+                server.stats.session_error();
+                warn!(error = %e, "disconnect with error");
+                return;
+            }
+        }
+    } else if settings.proxy_protocol {
+        info!("accept connection. the peer is not a trusted proxy, so no proxy header is read");
+        addr
+    } else {
+        info!("accept connection");
+        addr
+    };
+    let record_path = server.record_dir.as_ref().map(|dir| {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        dir.join(format!("{}-{}.{}", started.as_millis(), connection_id, recorder::EXT))
+    });
+    let limited = tokio::select! {
+        limited = permit.set_ip(addr.ip()) => limited,
+        _ = shutdown.wait(ShutdownState::Draining) => return,
+    };
+    if let Err(e) = limited {
+        reject_connection(&mut conn, &server, e).await;
+        return;
+    }
+    let registration = server.connections.register(connection_id, &server.name, &addr.to_string());
+    let info = registration.info().clone();
+    let recorder = match record_path {
+        None => None,
+        Some(path) => match Recorder::create(&path, &addr.to_string()).await {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!(path = %path.display(), error = %e, "create session recording error");
+                None
+            }
+        },
+    };
+    if !settings.is_put_allowed(addr.ip()) {
+        info!("client is not in the put allowlist. its puts are discarded");
+        options.set_put_allowed(false);
+    }
+    if let Some(access_log) = &server.access_log {
+        let client = addr.ip().map(|ip| ip.to_string()).unwrap_or_else(|| addr.to_string());
+        options.add_observer(access_log.observer(&server.name, &client));
+    }
+    options.set_close(Some(info.close_signal()));
+    options.add_observer(info.clone());
+    let _active = server.stats.session_started();
+    let (reader, writer) = tokio::io::split(CountingStream::new(conn, info));
+    let mut reader = BufReader::new(RecordingReader::new(reader, recorder));
+    let mut writer = BufWriter::new(writer);
+    match handle_with_options(&mut reader, &mut writer, server.handler.clone(), &options).await {
+        Ok(_) => {
+            info!("client quit");
+        }
+        Err(e) => {
+            server.stats.session_error();
+            warn!(error = %e, "disconnect with error");
+        }
+    }
+    drop(connection);
+}
+
+/// `GET /metrics`
+fn metrics_response(servers: &[Arc<Server>], request: Request) -> Response {
+    if request.path != "/metrics" {
+        return Response::not_found();
+    }
+    if request.method != "GET" {
+        return Response::method_not_allowed();
+    }
+    let backends: Vec<String> = servers.iter().map(|server| server.settings.read().unwrap().storage.backend.to_string()).collect();
+    let listeners: Vec<ListenerMetrics> = servers.iter().zip(backends.iter()).map(|(server, backend)| ListenerMetrics {
+        name: &server.name,
+        backend,
+        stats: &server.stats,
+    }).collect();
+    Response::new(200, "text/plain; version=0.0.4", metrics::render(&listeners))
+}
+
+/// refresh the storage usage of a listener in its stats
+async fn measure_storage_usage(server: Arc<Server>, interval: Duration) {
+    loop {
+        match server.handler.storage_usage().await {
+            Ok(usage) => server.stats.set_storage_usage(usage),
+            Err(e) => warn!(listener = %server.name, error = %e, "measure storage usage error"),
+        }
+        sleep(interval).await;
+    }
+}
+
+/// log the stats summary of every listener
+fn log_stats(servers: &[Arc<Server>]) {
+    for server in servers.iter() {
+        info!(listener = %server.name, "stats: {}", server.stats);
+    }
+}
+
+async fn log_stats_periodically(servers: Vec<Arc<Server>>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        log_stats(&servers);
+    }
+}
+
+/// log the stats and the live connections on every SIGUSR1
+#[cfg(unix)]
+async fn dump_on_signal(servers: Vec<Arc<Server>>, registry: Arc<Connections>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut user_defined1 = match signal(SignalKind::user_defined1()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!(error = %e, "listen SIGUSR1 error");
+            return;
+        }
+    };
+    while user_defined1.recv().await.is_some() {
+        log_stats(&servers);
+        let connections = registry.list();
+        info!(count = connections.len(), "live connections");
+        for connection in connections {
+            let (command, since) = match connection.current_command() {
+                Some((kind, since)) => (Some(kind.as_str()), Some(humantime::format_rfc3339_millis(since))),
+                None => (None, None),
+            };
+            info!(id = connection.id(), listener = connection.listener(), peer = connection.peer(),
+                  started_at = %humantime::format_rfc3339_millis(connection.started_at()),
+                  command, since = since.map(field::display), bytes_read = connection.bytes_read(), bytes_written = connection.bytes_written(),
+                  "connection");
+        }
+    }
+}
+
+/// log the top keys of every listener
+async fn log_hot_keys(servers: Vec<Arc<Server>>, interval: Duration, top: usize) {
+    let summary = |counts: Vec<HotKeyCount>| -> String {
+        counts.iter().map(|count| format!("{}={}", count.key, count.count)).collect::<Vec<_>>().join(" ")
+    };
+    loop {
+        sleep(interval).await;
+        for server in servers.iter() {
+            if let Some(hot_keys) = &server.hot_keys {
+                let window = hot_keys.window();
+                info!(listener = %server.name, ?window, "hot keys by requests: {}", summary(hot_keys.top_requests(top)));
+                info!(listener = %server.name, ?window, "hot keys by bytes: {}", summary(hot_keys.top_bytes(top)));
+            }
+        }
+    }
+}
+
+/// re-read the config on every SIGHUP and apply the settings which can change without a restart:
+/// log level, limits, max file size, put allowlists, timeouts and pipeline depth.
+/// new connections get the new settings. open connections keep theirs, except for the limits.
+#[cfg(unix)]
+async fn reload_on_signal(args: Args, config: Config, log_level: LogLevelHandle, servers: Vec<Arc<Server>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!(error = %e, "listen SIGHUP error");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let new_config = match Config::load(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("reload config error: {:#}", e);
+                continue;
+            }
+        };
+        if let Err(e) = log_level.reload(LevelFilter::from(new_config.log.level)) {
+            error!(error = %e, "reload log level error");
+        }
+        let new_listeners = new_config.listeners();
+        for server in servers.iter() {
+            let new_settings = match new_listeners.iter().find(|settings| settings.name == server.name) {
+                Some(settings) => settings,
+                None => continue,
+            };
+            let settings = {
+                let mut settings = server.settings.write().unwrap();
+                settings.reload(new_settings);
+                settings.clone()
+            };
+            let limits = &settings.limits;
+            server.limiter.set_limits(limits.max_connections, limits.max_connections_per_ip, limits.policy.into());
+            let mut options = server.options.write().unwrap();
+            let mut new_options = new_config.handle_options(&settings);
+            new_options.set_shutdown(options.shutdown().cloned());
+            new_options.set_mode(options.mode().clone());
+            for observer in options.observers() {
+                new_options.add_observer(observer.clone());
+            }
+            *options = new_options;
+        }
+        // compared with the config at startup, which is what runs until a restart
+        for change in config.restart_required_changes(&new_config) {
+            warn!(change, "config change needs a restart");
+        }
+        info!(level = ?new_config.log.level, "config reloaded");
+    }
+}
+
+/// switch to the next mode on every SIGUSR2
+#[cfg(unix)]
+async fn switch_mode_on_signal(mode: ModeSwitch) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut user_defined2 = match signal(SignalKind::user_defined2()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!(error = %e, "listen SIGUSR2 error");
+            return;
+        }
+    };
+    info!(mode = %mode.get(), "send SIGUSR2 to switch mode");
+    while user_defined2.recv().await.is_some() {
+        info!(mode = %mode.cycle(), "mode switched");
+    }
+}
+
+/// wait for SIGINT, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!(error = %e, "listen SIGTERM error"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(error = %e, "listen SIGINT error");
+        std::future::pending::<()>().await;
+    }
+}
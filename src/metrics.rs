@@ -0,0 +1,181 @@
+//! Prometheus text exposition format
+//! <https://prometheus.io/docs/instrumenting/exposition_formats/>
+
+use std::fmt::Write;
+
+use crate::UnityFileType;
+use crate::stats::{Histogram, LATENCY_BUCKETS, Stats};
+
+const FILE_TYPES: [UnityFileType; UnityFileType::LENGTH] = [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource];
+
+/// The stats of one listener
+#[derive(Debug, Copy, Clone)]
+pub struct ListenerMetrics<'a> {
+    pub name: &'a str,
+    pub backend: &'a str,
+    pub stats: &'a Stats,
+}
+
+/// render the metrics of all listeners
+pub fn render(listeners: &[ListenerMetrics]) -> String {
+    let mut out = String::new();
+    counter(&mut out, "ucs_connections_accepted_total", "Connections accepted, including the rejected ones", listeners, |stats| stats.accepted());
+    counter(&mut out, "ucs_connections_rejected_total", "Connections closed by the limits", listeners, |stats| stats.rejected());
+    counter(&mut out, "ucs_session_errors_total", "Sessions ended by an error", listeners, |stats| stats.errors());
+    gauge(&mut out, "ucs_connections_active", "Connections being served", listeners, |stats| stats.active());
+    counter(&mut out, "ucs_transactions_committed_total", "Transactions committed by the handler", listeners, |stats| stats.transactions_committed());
+    counter(&mut out, "ucs_transactions_cancelled_total", "Transactions cancelled, replaced or failed to commit", listeners, |stats| stats.transactions_cancelled());
+    gauge(&mut out, "ucs_open_transactions", "Transactions started and not yet committed or cancelled", listeners, |stats| stats.open_transactions());
+
+    header(&mut out, "ucs_get_hits_total", "counter", "Gets answered with the file");
+    for listener in listeners {
+        for t in FILE_TYPES {
+            let _ = writeln!(out, "ucs_get_hits_total{{listener=\"{}\",file_type=\"{}\"}} {}", escape(listener.name), t.to_ext(), listener.stats.hits(t));
+        }
+    }
+    header(&mut out, "ucs_get_misses_total", "counter", "Gets answered with a miss");
+    for listener in listeners {
+        for t in FILE_TYPES {
+            let _ = writeln!(out, "ucs_get_misses_total{{listener=\"{}\",file_type=\"{}\"}} {}", escape(listener.name), t.to_ext(), listener.stats.misses(t));
+        }
+    }
+    counter(&mut out, "ucs_sent_bytes_total", "File bytes sent by gets", listeners, |stats| stats.bytes_sent());
+    counter(&mut out, "ucs_received_bytes_total", "File bytes received by puts", listeners, |stats| stats.bytes_received());
+
+    header(&mut out, "ucs_put_rejections_total", "counter", "Puts rejected by the handler, by error");
+    for listener in listeners {
+        for (error, count) in listener.stats.put_rejections() {
+            let _ = writeln!(out, "ucs_put_rejections_total{{listener=\"{}\",error=\"{}\"}} {}", escape(listener.name), error, count);
+        }
+    }
+
+    header(&mut out, "ucs_handler_duration_seconds", "histogram", "Time spent in the handler. A put includes receiving the file.");
+    for listener in listeners {
+        histogram(&mut out, listener.name, "get", listener.stats.get_latency());
+        histogram(&mut out, listener.name, "put", listener.stats.put_latency());
+        histogram(&mut out, listener.name, "end_transaction", listener.stats.end_transaction_latency());
+    }
+
+    header(&mut out, "ucs_storage_files", "gauge", "Files in the storage");
+    for listener in listeners {
+        if let Some(usage) = listener.stats.storage_usage() {
+            let _ = writeln!(out, "ucs_storage_files{{listener=\"{}\",backend=\"{}\"}} {}", escape(listener.name), listener.backend, usage.files);
+        }
+    }
+    header(&mut out, "ucs_storage_bytes", "gauge", "Bytes of the files in the storage");
+    for listener in listeners {
+        if let Some(usage) = listener.stats.storage_usage() {
+            let _ = writeln!(out, "ucs_storage_bytes{{listener=\"{}\",backend=\"{}\"}} {}", escape(listener.name), listener.backend, usage.bytes);
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, listeners: &[ListenerMetrics], value: impl Fn(&Stats) -> u64) {
+    header(out, name, "counter", help);
+    for listener in listeners {
+        let _ = writeln!(out, "{}{{listener=\"{}\"}} {}", name, escape(listener.name), value(listener.stats));
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, listeners: &[ListenerMetrics], value: impl Fn(&Stats) -> u64) {
+    header(out, name, "gauge", help);
+    for listener in listeners {
+        let _ = writeln!(out, "{}{{listener=\"{}\"}} {}", name, escape(listener.name), value(listener.stats));
+    }
+}
+
+fn histogram(out: &mut String, listener: &str, operation: &str, histogram: &Histogram) {
+    let name = "ucs_handler_duration_seconds";
+    let labels = format!("listener=\"{}\",operation=\"{}\"", escape(listener), operation);
+    let counts = histogram.cumulative_counts();
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(counts.iter()) {
+        let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+    }
+    let total = counts.last().copied().unwrap_or_default();
+    let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, total);
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum().as_secs_f64());
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, total);
+}
+
+/// escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{CommandKind, CommandObserver, CommandOutcome, CommandRecord, StorageUsage};
+
+    use super::*;
+
+    fn record(kind: CommandKind, file_type: Option<UnityFileType>, outcome: CommandOutcome, bytes: u64, handler_millis: f64) -> CommandRecord {
+        CommandRecord {
+            kind,
+            file_type,
+            guid: None,
+            hash: None,
+            outcome,
+            bytes,
+            started_at: SystemTime::now(),
+            duration: Duration::ZERO,
+            handler_time: Some(Duration::from_secs_f64(handler_millis / 1000.0)),
+        }
+    }
+
+    #[test]
+    fn render_listeners() {
+        let a = Stats::new();
+        a.connection_accepted();
+        a.on_command(record(CommandKind::Get, Some(UnityFileType::Asset), CommandOutcome::Hit, 100, 0.3));
+        a.on_command(record(CommandKind::Get, Some(UnityFileType::Info), CommandOutcome::Miss, 0, 20.0));
+        a.on_command(record(CommandKind::Put, Some(UnityFileType::Resource), CommandOutcome::Ok, 50, 3.0));
+        a.on_command(record(CommandKind::Put, Some(UnityFileType::Resource), CommandOutcome::Rejected("FileTooLarge"), 0, 1.0));
+        a.on_command(record(CommandKind::Put, Some(UnityFileType::Resource), CommandOutcome::Rejected("FileTooLarge"), 0, 1.0));
+        a.on_command(record(CommandKind::Put, Some(UnityFileType::Asset), CommandOutcome::Rejected("HandlerError"), 0, 1.0));
+        a.set_storage_usage(Some(StorageUsage { files: 3, bytes: 150 }));
+        let b = Stats::new();
+        let text = render(&[
+            ListenerMetrics { name: "a", backend: "fs", stats: &a },
+            ListenerMetrics { name: "b\"", backend: "memory", stats: &b },
+        ]);
+        let lines: Vec<&str> = text.lines().collect();
+        for line in [
+            "# TYPE ucs_connections_accepted_total counter",
+            "ucs_connections_accepted_total{listener=\"a\"} 1",
+            "ucs_connections_accepted_total{listener=\"b\\\"\"} 0",
+            "ucs_get_hits_total{listener=\"a\",file_type=\"bin\"} 1",
+            "ucs_get_hits_total{listener=\"a\",file_type=\"info\"} 0",
+            "ucs_get_misses_total{listener=\"a\",file_type=\"info\"} 1",
+            "ucs_sent_bytes_total{listener=\"a\"} 100",
+            "ucs_received_bytes_total{listener=\"a\"} 50",
+            "ucs_put_rejections_total{listener=\"a\",error=\"FileTooLarge\"} 2",
+            "ucs_put_rejections_total{listener=\"a\",error=\"HandlerError\"} 1",
+            "# TYPE ucs_handler_duration_seconds histogram",
+            "ucs_handler_duration_seconds_bucket{listener=\"a\",operation=\"get\",le=\"0.0005\"} 1",
+            "ucs_handler_duration_seconds_bucket{listener=\"a\",operation=\"get\",le=\"0.01\"} 1",
+            "ucs_handler_duration_seconds_bucket{listener=\"a\",operation=\"get\",le=\"0.025\"} 2",
+            "ucs_handler_duration_seconds_bucket{listener=\"a\",operation=\"get\",le=\"10\"} 2",
+            "ucs_handler_duration_seconds_bucket{listener=\"a\",operation=\"get\",le=\"+Inf\"} 2",
+            "ucs_handler_duration_seconds_sum{listener=\"a\",operation=\"get\"} 0.0203",
+            "ucs_handler_duration_seconds_count{listener=\"a\",operation=\"get\"} 2",
+            "ucs_handler_duration_seconds_bucket{listener=\"a\",operation=\"put\",le=\"0.001\"} 3",
+            "ucs_handler_duration_seconds_count{listener=\"a\",operation=\"put\"} 4",
+            "ucs_handler_duration_seconds_count{listener=\"a\",operation=\"end_transaction\"} 0",
+            "ucs_storage_files{listener=\"a\",backend=\"fs\"} 3",
+            "ucs_storage_bytes{listener=\"a\",backend=\"fs\"} 150",
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+        // no rejections and no storage usage measured yet
+        assert!(!text.contains("ucs_put_rejections_total{listener=\"b\\\"\""));
+        assert!(!text.contains("ucs_storage_files{listener=\"b\\\"\""));
+    }
+}
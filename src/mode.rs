@@ -0,0 +1,122 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::Deserialize;
+
+/// How the server treats the commands. Switched at runtime, e.g. during disk migrations.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    Normal,
+    /// puts are discarded and transactions are not committed. gets are served.
+    ReadOnly,
+    /// every get is a miss. puts are stored.
+    PassThroughMiss,
+}
+
+impl Mode {
+    fn to_u8(self) -> u8 {
+        match self {
+            Mode::Normal => 0,
+            Mode::ReadOnly => 1,
+            Mode::PassThroughMiss => 2,
+        }
+    }
+
+    fn from_u8(b: u8) -> Self {
+        match b {
+            1 => Mode::ReadOnly,
+            2 => Mode::PassThroughMiss,
+            _ => Mode::Normal,
+        }
+    }
+
+    /// the mode after this one: normal, read-only, pass-through-miss, then normal again
+    pub fn next(self) -> Self {
+        Self::from_u8((self.to_u8() + 1) % 3)
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Normal => write!(f, "normal"),
+            Mode::ReadOnly => write!(f, "read-only"),
+            Mode::PassThroughMiss => write!(f, "pass-through-miss"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Mode::Normal),
+            "read-only" => Ok(Mode::ReadOnly),
+            "pass-through-miss" => Ok(Mode::PassThroughMiss),
+            _ => Err(format!("unknown mode {:?}. expected normal, read-only or pass-through-miss", s)),
+        }
+    }
+}
+
+/// Shared mode of all the sessions. Sessions see a change at their next command.
+#[derive(Debug, Clone, Default)]
+pub struct ModeSwitch(Arc<AtomicU8>);
+
+impl ModeSwitch {
+    pub fn new(mode: Mode) -> Self {
+        Self(Arc::new(AtomicU8::new(mode.to_u8())))
+    }
+
+    pub fn get(&self) -> Mode {
+        Mode::from_u8(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, mode: Mode) {
+        self.0.store(mode.to_u8(), Ordering::Relaxed);
+    }
+
+    /// switch to the next mode and return it
+    pub fn cycle(&self) -> Mode {
+        let previous = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| Some(Mode::from_u8(b).next().to_u8()))
+            .unwrap_or_else(|b| b);
+        Mode::from_u8(previous).next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_cycles_through_all_modes() {
+        assert_eq!(Mode::Normal.next(), Mode::ReadOnly);
+        assert_eq!(Mode::ReadOnly.next(), Mode::PassThroughMiss);
+        assert_eq!(Mode::PassThroughMiss.next(), Mode::Normal);
+    }
+
+    #[test]
+    fn cycle_switches_every_clone() {
+        let switch = ModeSwitch::default();
+        let clone = switch.clone();
+        assert_eq!(switch.get(), Mode::Normal);
+        assert_eq!(switch.cycle(), Mode::ReadOnly);
+        assert_eq!(clone.get(), Mode::ReadOnly);
+        assert_eq!(clone.cycle(), Mode::PassThroughMiss);
+        assert_eq!(switch.cycle(), Mode::Normal);
+        assert_eq!(clone.get(), Mode::Normal);
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for mode in [Mode::Normal, Mode::ReadOnly, Mode::PassThroughMiss] {
+            assert_eq!(mode.to_string().parse::<Mode>(), Ok(mode));
+        }
+        assert_eq!("read-only".parse::<Mode>(), Ok(Mode::ReadOnly));
+        assert!("readonly".parse::<Mode>().is_err());
+        assert!("".parse::<Mode>().is_err());
+    }
+}
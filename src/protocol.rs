@@ -0,0 +1,328 @@
+use std::io::ErrorKind;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Error, HexString, Result, u32_to_be_hex_string, UnityFileGuid, UnityFileHash, UnityFileType};
+
+pub const PROTOCOL_VERSION: u32 = 254;
+
+/// client to server command
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// `ga` `gi` `gr`: get a file
+    Get(UnityFileType, UnityFileGuid, UnityFileHash),
+    /// `ts`: start a transaction
+    TransactionStart(UnityFileGuid, UnityFileHash),
+    /// `te`: commit the transaction
+    TransactionEnd,
+    /// `pa` `pi` `pr`: put a file. `size` bytes of payload follow the header.
+    Put(UnityFileType, u64),
+    /// `q`: quit
+    Quit,
+}
+
+impl Command {
+    /// read a command
+    /// return None if the stream ends before the first byte of a command
+    pub async fn read_from<R>(reader: &mut R) -> Result<Option<Self>>
+        where
+            R: AsyncRead + Unpin + ?Sized
+    {
+        let b = match reader.read_u8().await {
+            Ok(b) => b,
+            Err(e) => {
+                return if e.kind() == ErrorKind::UnexpectedEof {
+                    Ok(None)
+                } else {
+                    Err(Error::IoError(e))
+                };
+            }
+        };
+        let command = match b {
+            b'g' => {
+                let b2 = reader.read_u8().await?;
+                let t = UnityFileType::try_from_ext_char(b2).map_err(|_| Error::UnknownFileTypeByte(b2))?;
+                let guid = read_hex_string(reader).await?;
+                let hash = read_hex_string(reader).await?;
+                Command::Get(t, guid, hash)
+            }
+            b't' => match reader.read_u8().await? {
+                b's' => {
+                    let guid = read_hex_string(reader).await?;
+                    let hash = read_hex_string(reader).await?;
+                    Command::TransactionStart(guid, hash)
+                }
+                b'e' => Command::TransactionEnd,
+                b => return Err(Error::UnknownTransactionCommand(b)),
+            },
+            b'p' => {
+                let b2 = reader.read_u8().await?;
+                let t = UnityFileType::try_from_ext_char(b2).map_err(|_| Error::UnknownPushCommand(b2))?;
+                let size = read_size_string(reader).await?;
+                Command::Put(t, size)
+            }
+            b'q' => Command::Quit,
+            b => return Err(Error::UnknownCommand(b)),
+        };
+        Ok(Some(command))
+    }
+
+    /// write a command. the payload of `Put` is not included.
+    pub async fn write_to<W>(&self, writer: &mut W) -> Result<()>
+        where
+            W: AsyncWrite + Unpin + ?Sized
+    {
+        match self {
+            Command::Get(t, guid, hash) => {
+                writer.write_all(&[b'g', t.to_ext_char()]).await?;
+                writer.write_all(guid.as_ref()).await?;
+                writer.write_all(hash.as_ref()).await?;
+            }
+            Command::TransactionStart(guid, hash) => {
+                writer.write_all(b"ts").await?;
+                writer.write_all(guid.as_ref()).await?;
+                writer.write_all(hash.as_ref()).await?;
+            }
+            Command::TransactionEnd => {
+                writer.write_all(b"te").await?;
+            }
+            Command::Put(t, size) => {
+                writer.write_all(&[b'p', t.to_ext_char()]).await?;
+                write_size_string(writer, *size).await?;
+            }
+            Command::Quit => {
+                writer.write_all(b"q").await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// server to client response
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Response {
+    /// reply to the version handshake. the client sends its version in the same format.
+    Version(u32),
+    /// `+` type size guid hash: file found. `size` bytes of file content follow the header.
+    Hit(UnityFileType, u64, UnityFileGuid, UnityFileHash),
+    /// `-` type guid hash: file not found
+    Miss(UnityFileType, UnityFileGuid, UnityFileHash),
+}
+
+impl Response {
+    /// read the reply to the version handshake
+    pub async fn read_version_from<R>(reader: &mut R) -> Result<Self>
+        where
+            R: AsyncRead + Unpin + ?Sized
+    {
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).await?;
+        Ok(Response::Version(u32::from_str_radix(std::str::from_utf8(&buf)?, 16)?))
+    }
+
+    /// read the response of a get command. the file content of `Hit` is not included.
+    pub async fn read_from<R>(reader: &mut R) -> Result<Self>
+        where
+            R: AsyncRead + Unpin + ?Sized
+    {
+        let b = reader.read_u8().await?;
+        let b2 = reader.read_u8().await?;
+        let t = UnityFileType::try_from_ext_char(b2).map_err(|_| Error::UnknownFileTypeByte(b2))?;
+        match b {
+            b'+' => {
+                let size = read_size_string(reader).await?;
+                let guid = read_hex_string(reader).await?;
+                let hash = read_hex_string(reader).await?;
+                Ok(Response::Hit(t, size, guid, hash))
+            }
+            b'-' => {
+                let guid = read_hex_string(reader).await?;
+                let hash = read_hex_string(reader).await?;
+                Ok(Response::Miss(t, guid, hash))
+            }
+            b => Err(Error::UnknownResponse(b)),
+        }
+    }
+
+    /// write a response. the file content of `Hit` is not included.
+    pub async fn write_to<W>(&self, writer: &mut W) -> Result<()>
+        where
+            W: AsyncWrite + Unpin + ?Sized
+    {
+        match self {
+            Response::Version(version) => {
+                writer.write_all(u32_to_be_hex_string(*version).as_bytes()).await?;
+            }
+            Response::Hit(t, size, guid, hash) => {
+                writer.write_all(&[b'+', t.to_ext_char()]).await?;
+                write_size_string(writer, *size).await?;
+                writer.write_all(guid.as_ref()).await?;
+                writer.write_all(hash.as_ref()).await?;
+            }
+            Response::Miss(t, guid, hash) => {
+                writer.write_all(&[b'-', t.to_ext_char()]).await?;
+                writer.write_all(guid.as_ref()).await?;
+                writer.write_all(hash.as_ref()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// read the version sent by the client at the beginning of a session
+pub async fn read_version<R>(reader: &mut R) -> Result<u32>
+    where
+        R: AsyncRead + Unpin + ?Sized
+{
+    let mut buf = vec![0u8; 8];
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+        return Err(Error::ReadVersionError);
+    }
+
+    let n = if n == 1 {
+        let n2 = reader.read(&mut buf[n..8]).await?;
+        if n2 == 0 {
+            return Err(Error::ReadVersionError);
+        }
+        if n + n2 > 8 {
+            unreachable!("The buffer is 8 bytes len. So it cannot read more than 8 bytes");
+        }
+        n + n2
+    } else {
+        n
+    };
+
+    Ok(u32::from_str_radix(std::str::from_utf8(&buf[0..n])?, 16)?)
+}
+
+pub async fn read_hex_string<R, const N: usize>(reader: &mut R) -> Result<HexString<N>>
+    where
+        R: AsyncRead + Unpin + ?Sized
+{
+    let mut s = HexString::new();
+    reader.read_exact(&mut s.0).await?;
+    Ok(s)
+}
+
+pub async fn read_size_string<R>(reader: &mut R) -> Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized
+{
+    const U64_STRING_LENGTH: usize = 16;
+
+    let mut buf = [0u8; U64_STRING_LENGTH];
+    reader.read_exact(&mut buf).await?;
+    Ok(u64::from_str_radix(std::str::from_utf8(&buf)?, 16)?)
+}
+
+pub async fn write_size_string<W>(writer: &mut W, v: u64) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized
+{
+    writer.write_all(format!("{:016x}", v).as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guid() -> UnityFileGuid {
+        HexString([0x11; 16])
+    }
+
+    fn hash() -> UnityFileHash {
+        HexString([0xab; 16])
+    }
+
+    async fn command_round_trip(command: Command) {
+        let mut buf = Vec::new();
+        command.write_to(&mut buf).await.unwrap();
+        let mut reader = &buf[..];
+        assert_eq!(Command::read_from(&mut reader).await.unwrap(), Some(command));
+        assert!(reader.is_empty());
+    }
+
+    async fn response_round_trip(response: Response) {
+        let mut buf = Vec::new();
+        response.write_to(&mut buf).await.unwrap();
+        let mut reader = &buf[..];
+        let read = match response {
+            Response::Version(_) => Response::read_version_from(&mut reader).await.unwrap(),
+            _ => Response::read_from(&mut reader).await.unwrap(),
+        };
+        assert_eq!(read, response);
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn command_round_trips() {
+        for t in [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource] {
+            command_round_trip(Command::Get(t, guid(), hash())).await;
+            command_round_trip(Command::Put(t, 0x1234_5678_9abc)).await;
+        }
+        command_round_trip(Command::TransactionStart(guid(), hash())).await;
+        command_round_trip(Command::TransactionEnd).await;
+        command_round_trip(Command::Quit).await;
+    }
+
+    #[tokio::test]
+    async fn response_round_trips() {
+        response_round_trip(Response::Version(PROTOCOL_VERSION)).await;
+        for t in [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource] {
+            response_round_trip(Response::Hit(t, 42, guid(), hash())).await;
+            response_round_trip(Response::Miss(t, guid(), hash())).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn command_wire_format() {
+        let mut buf = Vec::new();
+        Command::Put(UnityFileType::Info, 255).write_to(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pi00000000000000ff");
+        let mut buf = Vec::new();
+        Response::Version(PROTOCOL_VERSION).write_to(&mut buf).await.unwrap();
+        assert_eq!(buf, b"000000fe");
+    }
+
+    #[tokio::test]
+    async fn empty_stream_is_no_command() {
+        assert_eq!(Command::read_from(&mut &b""[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn command_errors() {
+        assert!(matches!(Command::read_from(&mut &b"x"[..]).await, Err(Error::UnknownCommand(b'x'))));
+        assert!(matches!(Command::read_from(&mut &b"tx"[..]).await, Err(Error::UnknownTransactionCommand(b'x'))));
+        assert!(matches!(Command::read_from(&mut &b"px0000000000000001"[..]).await, Err(Error::UnknownPushCommand(b'x'))));
+        assert!(matches!(Command::read_from(&mut &b"gx"[..]).await, Err(Error::UnknownFileTypeByte(b'x'))));
+        assert!(matches!(Command::read_from(&mut &b"pa000000000000000z"[..]).await, Err(Error::ParseIntError(_))));
+    }
+
+    #[tokio::test]
+    async fn truncated_command_is_an_io_error() {
+        for truncated in [&b"g"[..], b"ga0123", b"ts", b"pa0000"] {
+            match Command::read_from(&mut &truncated[..]).await {
+                Err(Error::IoError(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+                other => panic!("{:?}: {:?}", truncated, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn response_errors() {
+        assert!(matches!(Response::read_from(&mut &b"*a"[..]).await, Err(Error::UnknownResponse(b'*'))));
+        assert!(matches!(Response::read_from(&mut &b"+x"[..]).await, Err(Error::UnknownFileTypeByte(b'x'))));
+        assert!(matches!(Response::read_from(&mut &b"-a0123"[..]).await, Err(Error::IoError(_))));
+        assert!(matches!(Response::read_version_from(&mut &b"000000zz"[..]).await, Err(Error::ParseIntError(_))));
+    }
+
+    #[tokio::test]
+    async fn read_version_of_client() {
+        assert_eq!(read_version(&mut &b"000000fe"[..]).await.unwrap(), PROTOCOL_VERSION);
+        // old clients send a shorter version
+        assert_eq!(read_version(&mut &b"fe"[..]).await.unwrap(), PROTOCOL_VERSION);
+        assert!(matches!(read_version(&mut &b""[..]).await, Err(Error::ReadVersionError)));
+    }
+}
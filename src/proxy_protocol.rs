@@ -0,0 +1,230 @@
+//! HAProxy PROXY protocol v1 and v2
+//! <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{Error, Result};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// max length of a v1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// read the PROXY protocol header sent by a load balancer before the client data.
+/// return the address of the client, None if the balancer connected on its own behalf, e.g. a health check.
+pub async fn read_header<R>(reader: &mut R) -> Result<Option<SocketAddr>>
+    where
+        R: AsyncRead + Unpin + ?Sized
+{
+    // 12 bytes is the v2 signature and shorter than any v1 header
+    let mut head = [0u8; 12];
+    reader.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if head.starts_with(b"PROXY ") {
+        read_v1(reader, head).await
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidProxyHeader(reason.to_string())
+}
+
+/// `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`
+async fn read_v1<R>(reader: &mut R, head: [u8; 12]) -> Result<Option<SocketAddr>>
+    where
+        R: AsyncRead + Unpin + ?Sized
+{
+    let mut line = head.to_vec();
+    // read byte by byte to not consume the client data after the header
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("v1 invalid source address"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("v1 address does not match the protocol"));
+            }
+            let port: u16 = source_port.parse().map_err(|_| invalid("v1 invalid source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("v1 malformed header")),
+    }
+}
+
+/// 12 bytes signature, version and command, family and protocol, u16 length of the addresses, addresses
+async fn read_v2<R>(reader: &mut R) -> Result<Option<SocketAddr>>
+    where
+        R: AsyncRead + Unpin + ?Sized
+{
+    let version_command = reader.read_u8().await?;
+    let family_protocol = reader.read_u8().await?;
+    let len = reader.read_u16().await? as usize;
+    let mut addresses = vec![0u8; len];
+    reader.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("v2 unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("v2 unknown command")),
+    }
+    // the low nibble is the transport protocol. any of them carries the addresses.
+    match family_protocol >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 => {
+            if addresses.len() < 12 {
+                return Err(invalid("v2 ipv4 addresses too short"));
+            }
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 => {
+            if addresses.len() < 36 {
+                return Err(invalid("v2 ipv6 addresses too short"));
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[0..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // AF_UNSPEC, AF_UNIX: no ip address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the header, and the client data after it
+    async fn read(data: &[u8]) -> (Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut reader = data;
+        let result = read_header(&mut reader).await;
+        (result, reader.to_vec())
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn is_invalid(result: Result<Option<SocketAddr>>) -> bool {
+        matches!(result, Err(Error::InvalidProxyHeader(_)))
+    }
+
+    fn is_eof(result: Result<Option<SocketAddr>>) -> bool {
+        matches!(result, Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+    }
+
+    #[tokio::test]
+    async fn v1_addresses() {
+        let (result, rest) = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 8126\r\n000000fe").await;
+        assert_eq!(result.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"000000fe");
+
+        let (result, _) = read(b"PROXY TCP6 fd00::1 fd00::2 56324 8126\r\n").await;
+        assert_eq!(result.unwrap(), Some("[fd00::1]:56324".parse().unwrap()));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nq").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"q");
+        let (result, _) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_invalid() {
+        assert!(is_invalid(read(b"PROXY TCP4 fd00::1 fd00::2 1 2\r\n").await.0));
+        assert!(is_invalid(read(b"PROXY TCP4 192.168.0.1 192.168.0.11 port 8126\r\n").await.0));
+        assert!(is_invalid(read(b"PROXY TCP4 192.168.0.1 192.168.0.11\r\n").await.0));
+        assert!(is_invalid(read(b"PROXY UDP4 192.168.0.1 192.168.0.11 1 2\r\n").await.0));
+        assert!(is_eof(read(b"PROXY TCP4 192.168.0.1 192.168").await.0));
+
+        let mut oversized = b"PROXY UNKNOWN ".to_vec();
+        oversized.resize(200, b'x');
+        oversized.extend_from_slice(b"\r\n");
+        let (result, rest) = read(&oversized).await;
+        assert!(is_invalid(result));
+        // no more than the longest valid header is read
+        assert_eq!(rest.len(), oversized.len() - V1_MAX_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn v2_addresses() {
+        let ipv4 = [10, 0, 0, 5, 10, 0, 0, 1, 0xdb, 0xfc, 0x1f, 0xbe];
+        let mut data = v2_header(1, 1, &ipv4);
+        data.extend_from_slice(b"000000fe");
+        let (result, rest) = read(&data).await;
+        assert_eq!(result.unwrap(), Some("10.0.0.5:56316".parse().unwrap()));
+        assert_eq!(rest, b"000000fe");
+
+        let mut ipv6 = Vec::new();
+        ipv6.extend_from_slice(&"fd00::5".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&[0xdb, 0xfc, 0x1f, 0xbe]);
+        let (result, _) = read(&v2_header(1, 2, &ipv6)).await;
+        assert_eq!(result.unwrap(), Some("[fd00::5]:56316".parse().unwrap()));
+
+        // extra bytes after the addresses, e.g. TLVs, are skipped
+        let mut with_tlv = ipv4.to_vec();
+        with_tlv.extend_from_slice(&[0x04, 0x00, 0x01, 0xaa]);
+        let mut data = v2_header(1, 1, &with_tlv);
+        data.push(b'q');
+        let (result, rest) = read(&data).await;
+        assert_eq!(result.unwrap(), Some("10.0.0.5:56316".parse().unwrap()));
+        assert_eq!(rest, b"q");
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unspec_have_no_address() {
+        let (result, rest) = read(&[v2_header(0, 0, &[]), b"q".to_vec()].concat()).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"q");
+        let (result, _) = read(&v2_header(0, 1, &[10, 0, 0, 5, 10, 0, 0, 1, 0, 1, 0, 2])).await;
+        assert_eq!(result.unwrap(), None);
+        let (result, _) = read(&v2_header(1, 0, &[])).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        assert!(is_invalid(read(&v2_header(1, 1, &[10, 0, 0, 5])).await.0));
+        assert!(is_invalid(read(&v2_header(1, 2, &[0; 12])).await.0));
+        assert!(is_invalid(read(&v2_header(2, 1, &[0; 12])).await.0));
+        let mut version_1 = v2_header(1, 1, &[0; 12]);
+        version_1[12] = 0x11;
+        assert!(is_invalid(read(&version_1).await.0));
+        // the length says more than is sent
+        let mut truncated = v2_header(1, 1, &[0; 12]);
+        truncated.truncate(20);
+        assert!(is_eof(read(&truncated).await.0));
+        assert!(is_eof(read(&V2_SIGNATURE[..8]).await.0));
+    }
+
+    #[tokio::test]
+    async fn missing_header() {
+        assert!(is_invalid(read(b"000000fe00000000").await.0));
+        assert!(is_eof(read(b"000000fe").await.0));
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Session recording file format
+///
+/// header: magic `UCSREC01`, u64 session start time (micros since unix epoch), u16 peer length, peer
+/// chunks: u64 offset from session start (micros), u32 length, bytes
+/// a chunk with length [`TRUNCATED`] and no bytes ends a recording which could not keep up with the client
+///
+/// all integers are little endian
+pub const MAGIC: &[u8; 8] = b"UCSREC01";
+
+/// Chunk length marking the end of a truncated recording
+pub const TRUNCATED: u32 = u32::MAX;
+
+/// Chunks waiting for the disk. The recording stops when more are queued.
+const MAX_QUEUED_CHUNKS: usize = 1024;
+
+pub const EXT: &str = "ucsrec";
+
+/// Writes the raw inbound bytes of one session to a file.
+/// The file is written by a background task, so reading from the client never waits for the disk.
+/// If the disk falls too far behind, the rest of the session is not recorded and the file is marked truncated.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    started: Instant,
+    sender: mpsc::Sender<Vec<u8>>,
+    truncated: Arc<AtomicBool>,
+}
+
+impl Recorder {
+    pub async fn create(path: impl AsRef<Path>, peer: &str) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = BufWriter::new(File::create(&path).await?);
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let peer = peer.as_bytes();
+        file.write_all(MAGIC).await?;
+        file.write_u64_le(started_at.as_micros() as u64).await?;
+        file.write_u16_le(peer.len() as u16).await?;
+        file.write_all(peer).await?;
+
+        let started = Instant::now();
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(MAX_QUEUED_CHUNKS);
+        let truncated = Arc::new(AtomicBool::new(false));
+        let writer_truncated = truncated.clone();
+        tokio::spawn(async move {
+            let result = async {
+                while let Some(chunk) = receiver.recv().await {
+                    file.write_all(&chunk).await?;
+                }
+                if writer_truncated.load(Ordering::Relaxed) {
+                    error!(path = %path.display(), "session recording is truncated. the disk is too slow");
+                    file.write_u64_le(started.elapsed().as_micros() as u64).await?;
+                    file.write_u32_le(TRUNCATED).await?;
+                }
+                file.flush().await
+            }.await;
+            if let Err(e) = result {
+                error!(path = %path.display(), error = %e, "write session recording error");
+            }
+        });
+
+        Ok(Self {
+            started,
+            sender,
+            truncated,
+        })
+    }
+
+    pub fn record(&self, data: &[u8]) {
+        // a gap would put the rest of the stream out of sync
+        if self.truncated.load(Ordering::Relaxed) {
+            return;
+        }
+        let offset = self.started.elapsed().as_micros() as u64;
+        let mut chunk = Vec::with_capacity(12 + data.len());
+        chunk.extend_from_slice(&offset.to_le_bytes());
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        match self.sender.try_send(chunk) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => self.truncated.store(true, Ordering::Relaxed),
+            // the writer task only stops after a write error, which it has already reported
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Tees everything read from the inner reader to a recorder
+#[derive(Debug)]
+pub struct RecordingReader<R> {
+    inner: R,
+    recorder: Option<Recorder>,
+}
+
+impl<R> RecordingReader<R> {
+    pub fn new(inner: R, recorder: Option<Recorder>) -> Self {
+        Self {
+            inner,
+            recorder,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RecordingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(recorder)) = (&poll, &self.recorder) {
+            let data = &buf.filled()[before..];
+            if !data.is_empty() {
+                recorder.record(data);
+            }
+        }
+        poll
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedChunk {
+    /// time since the session started
+    pub offset: Duration,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub peer: String,
+    pub started_at: SystemTime,
+    pub chunks: Vec<RecordedChunk>,
+    /// the recording stopped before the session ended
+    pub truncated: bool,
+}
+
+impl Recording {
+    pub async fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path).await?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a session recording"));
+        }
+        let started_at = UNIX_EPOCH + Duration::from_micros(reader.read_u64_le().await?);
+        let mut peer = vec![0u8; reader.read_u16_le().await? as usize];
+        reader.read_exact(&mut peer).await?;
+        let peer = String::from_utf8_lossy(&peer).into_owned();
+
+        let mut chunks = Vec::new();
+        let mut truncated = false;
+        loop {
+            let offset = match reader.read_u64_le().await {
+                Ok(offset) => Duration::from_micros(offset),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let length = reader.read_u32_le().await?;
+            if length == TRUNCATED {
+                truncated = true;
+                break;
+            }
+            let mut data = vec![0u8; length as usize];
+            reader.read_exact(&mut data).await?;
+            chunks.push(RecordedChunk { offset, data });
+        }
+
+        Ok(Self {
+            peer,
+            started_at,
+            chunks,
+            truncated,
+        })
+    }
+
+    /// the whole inbound byte stream of the session
+    pub fn bytes(&self) -> Vec<u8> {
+        self.chunks.iter().flat_map(|chunk| chunk.data.iter().copied()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("ucs-recorder-test-{}.{}", uuid::Uuid::new_v4(), EXT))
+    }
+
+    /// wait for the writer task to finish the file after the recorder is dropped
+    async fn open_when_done(path: &Path, chunks: usize, truncated: bool) -> Recording {
+        for _ in 0..200 {
+            if let Ok(recording) = Recording::open(path).await {
+                if recording.chunks.len() == chunks && recording.truncated == truncated {
+                    return recording;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("recording {} is not written", path.display());
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let path = temp_path();
+        let recorder = Recorder::create(&path, "10.0.0.1:5000").await.unwrap();
+        recorder.record(b"000000fe");
+        recorder.record(b"q");
+        drop(recorder);
+        let recording = open_when_done(&path, 2, false).await;
+        assert_eq!(recording.peer, "10.0.0.1:5000");
+        assert_eq!(recording.bytes(), b"000000feq");
+        assert!(recording.chunks[0].offset <= recording.chunks[1].offset);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_and_marks_truncated_when_the_writer_falls_behind() {
+        let path = temp_path();
+        let recorder = Recorder::create(&path, "peer").await.unwrap();
+        // the current thread runtime doesn't run the writer task until this test yields
+        for _ in 0..MAX_QUEUED_CHUNKS + 10 {
+            recorder.record(b"x");
+        }
+        drop(recorder);
+        let recording = open_when_done(&path, MAX_QUEUED_CHUNKS, true).await;
+        assert_eq!(recording.bytes().len(), MAX_QUEUED_CHUNKS);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+//! Offline cache effectiveness report from access logs and session recordings.
+//!
+//! An access log has the result of every get. A recording only has what the client sent, so its gets are
+//! replayed against a cache which starts empty and keeps every file put in the input.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io::{BufRead, Cursor};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::{HexString, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::protocol::{Command, read_version};
+use crate::recorder::{self, Recording};
+
+const FILE_TYPES: [UnityFileType; UnityFileType::LENGTH] = [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource];
+
+/// A file of a listener. Listeners never share files.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct Key {
+    listener: String,
+    file_type: UnityFileType,
+    guid: UnityFileGuid,
+    hash: UnityFileHash,
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    /// None if the result is unknown, i.e. from a recording
+    Get(Option<bool>),
+    Put,
+}
+
+#[derive(Debug, Clone)]
+struct Event {
+    time: SystemTime,
+    client: String,
+    key: Key,
+    action: Action,
+    /// file size. 0 for misses and the gets of recordings.
+    bytes: u64,
+}
+
+/// one line of the access log, as written by [`crate::access_log`]
+#[derive(Deserialize)]
+struct Entry {
+    timestamp: String,
+    listener: String,
+    client: String,
+    command: String,
+    file_type: Option<String>,
+    guid: Option<String>,
+    hash: Option<String>,
+    result: String,
+    bytes: u64,
+}
+
+/// Collects the gets and puts of access logs and recordings, then reports on them
+#[derive(Debug, Default)]
+pub struct Report {
+    events: Vec<Event>,
+    /// access log lines which are not a valid entry, e.g. cut by a crash
+    skipped_lines: u64,
+    /// whether some gets come from recordings
+    simulated: bool,
+    /// recordings which stop before their session ended
+    truncated_recordings: u64,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// add an access log or a session recording, told apart by the recording magic
+    pub async fn add_file(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut magic = Vec::with_capacity(recorder::MAGIC.len());
+        File::open(path).await
+            .with_context(|| format!("open {} failed", path.display()))?
+            .take(recorder::MAGIC.len() as u64)
+            .read_to_end(&mut magic).await
+            .with_context(|| format!("read {} failed", path.display()))?;
+        if magic == recorder::MAGIC {
+            return self.add_recording(path).await
+                .with_context(|| format!("read recording {} failed", path.display()));
+        }
+        let content = tokio::fs::read(path).await
+            .with_context(|| format!("read {} failed", path.display()))?;
+        self.add_access_log(&content);
+        Ok(())
+    }
+
+    fn add_access_log(&mut self, content: &[u8]) {
+        for line in Cursor::new(content).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => {
+                    self.skipped_lines += 1;
+                    continue;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Entry>(&line).ok().and_then(parse_entry) {
+                Some(Some(event)) => self.events.push(event),
+                // commands without a file, e.g. transactions
+                Some(None) => {}
+                None => self.skipped_lines += 1,
+            }
+        }
+    }
+
+    async fn add_recording(&mut self, path: &Path) -> anyhow::Result<()> {
+        let recording = Recording::open(path).await?;
+        if recording.truncated {
+            self.truncated_recordings += 1;
+        }
+        let client = client_ip(&recording.peer);
+        let bytes = recording.bytes();
+        // start position of every chunk in the byte stream
+        let mut chunk_starts = Vec::with_capacity(recording.chunks.len());
+        let mut position = 0u64;
+        for chunk in &recording.chunks {
+            chunk_starts.push((position, chunk.offset));
+            position += chunk.data.len() as u64;
+        }
+        let time_at = |position: u64| {
+            let i = chunk_starts.partition_point(|(start, _)| *start <= position);
+            recording.started_at + chunk_starts.get(i.saturating_sub(1)).map(|(_, offset)| *offset).unwrap_or_default()
+        };
+
+        let mut cursor = Cursor::new(&bytes[..]);
+        read_version(&mut cursor).await.context("no version handshake")?;
+        self.simulated = true;
+        // files of the open transaction. they count once it is committed.
+        let mut transaction = None;
+        loop {
+            let time = time_at(cursor.position());
+            let command = match Command::read_from(&mut cursor).await {
+                Ok(Some(command)) => command,
+                // a session cut in the middle of a command
+                Ok(None) | Err(_) => break,
+            };
+            match command {
+                Command::Get(file_type, guid, hash) => self.events.push(Event {
+                    time,
+                    client: client.clone(),
+                    key: Key { listener: String::new(), file_type, guid, hash },
+                    action: Action::Get(None),
+                    bytes: 0,
+                }),
+                Command::TransactionStart(guid, hash) => transaction = Some((guid, hash, Vec::new())),
+                Command::TransactionEnd => {
+                    if let Some((guid, hash, files)) = transaction.take() {
+                        for (file_type, size) in files {
+                            self.events.push(Event {
+                                time,
+                                client: client.clone(),
+                                key: Key { listener: String::new(), file_type, guid, hash },
+                                action: Action::Put,
+                                bytes: size,
+                            });
+                        }
+                    }
+                }
+                Command::Put(file_type, size) => {
+                    cursor.set_position(cursor.position().saturating_add(size));
+                    if let Some((_, _, files)) = &mut transaction {
+                        files.push((file_type, size));
+                    }
+                }
+                Command::Quit => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// the report as text. lists the `top` keys of each kind.
+    pub fn render(&self, top: usize) -> String {
+        let mut events: Vec<&Event> = self.events.iter().collect();
+        events.sort_by_key(|event| event.time);
+
+        let mut by_client: BTreeMap<&str, Counts> = BTreeMap::new();
+        let mut by_hour: BTreeMap<u64, Counts> = BTreeMap::new();
+        let mut by_type: HashMap<UnityFileType, TypeBytes> = HashMap::new();
+        let mut total = Counts::default();
+        // files known to be stored and their size
+        let mut stored: HashMap<&Key, u64> = HashMap::new();
+        // misses of keys not yet put
+        let mut pending_misses: HashMap<&Key, u64> = HashMap::new();
+        // misses followed by a put: number of misses and bytes put
+        let mut cold: HashMap<&Key, (u64, u64)> = HashMap::new();
+        // bytes put and whether they were read after
+        let mut puts: HashMap<&Key, (u64, bool)> = HashMap::new();
+        for event in events.iter() {
+            let type_bytes = by_type.entry(event.key.file_type).or_default();
+            match event.action {
+                Action::Get(result) => {
+                    let hit = result.unwrap_or_else(|| stored.contains_key(&event.key));
+                    let bytes = match result {
+                        Some(_) => event.bytes,
+                        None if hit => stored[&event.key],
+                        None => 0,
+                    };
+                    for counts in [&mut total, by_client.entry(&event.client).or_default(), by_hour.entry(hour(event.time)).or_default()] {
+                        counts.add(hit);
+                    }
+                    if hit {
+                        type_bytes.hits += 1;
+                        type_bytes.sent += bytes;
+                        stored.insert(&event.key, bytes);
+                        if let Some((_, read)) = puts.get_mut(&event.key) {
+                            *read = true;
+                        }
+                    } else {
+                        *pending_misses.entry(&event.key).or_default() += 1;
+                    }
+                }
+                Action::Put => {
+                    type_bytes.puts += 1;
+                    type_bytes.received += event.bytes;
+                    stored.insert(&event.key, event.bytes);
+                    if let Some(misses) = pending_misses.remove(&event.key) {
+                        let (total_misses, bytes) = cold.entry(&event.key).or_default();
+                        *total_misses += misses;
+                        *bytes += event.bytes;
+                    }
+                    let (bytes, read) = puts.entry(&event.key).or_default();
+                    *bytes += event.bytes;
+                    *read = false;
+                }
+            }
+        }
+
+        let mut out = String::new();
+        match (events.first(), events.last()) {
+            (Some(first), Some(last)) => {
+                let _ = writeln!(out, "period: {} to {}", humantime::format_rfc3339_seconds(first.time), humantime::format_rfc3339_seconds(last.time));
+            }
+            _ => {
+                let _ = writeln!(out, "no gets or puts");
+                return out;
+            }
+        }
+        let _ = writeln!(out, "gets: {}, hits: {}, misses: {}, hit rate: {}", total.gets, total.hits, total.gets - total.hits, total.hit_rate());
+        if self.simulated {
+            let _ = writeln!(out, "the gets of recordings are replayed against a cache which starts empty and keeps every put");
+        }
+        if self.truncated_recordings > 0 {
+            let _ = writeln!(out, "{} recordings are truncated and miss the end of their session", self.truncated_recordings);
+        }
+        if self.skipped_lines > 0 {
+            let _ = writeln!(out, "skipped {} invalid access log lines", self.skipped_lines);
+        }
+
+        let _ = writeln!(out, "\nhit rate by client");
+        let mut clients: Vec<(&&str, &Counts)> = by_client.iter().collect();
+        clients.sort_by_key(|(_, counts)| std::cmp::Reverse(counts.gets));
+        let _ = writeln!(out, "  {:<40} {:>10} {:>10} {:>8}", "client", "gets", "hits", "rate");
+        for (client, counts) in clients {
+            let _ = writeln!(out, "  {:<40} {:>10} {:>10} {:>8}", client, counts.gets, counts.hits, counts.hit_rate());
+        }
+
+        let _ = writeln!(out, "\nhit rate by hour (UTC)");
+        let _ = writeln!(out, "  {:<20} {:>10} {:>10} {:>8}", "hour", "gets", "hits", "rate");
+        for (hour, counts) in by_hour.iter() {
+            let start = UNIX_EPOCH + Duration::from_secs(hour * 3600);
+            let _ = writeln!(out, "  {:<20} {:>10} {:>10} {:>8}", humantime::format_rfc3339_seconds(start), counts.gets, counts.hits, counts.hit_rate());
+        }
+
+        let cold_misses: u64 = cold.values().map(|(misses, _)| misses).sum();
+        let cold_bytes: u64 = cold.values().map(|(_, bytes)| bytes).sum();
+        let _ = writeln!(out, "\nmissed keys put later (cold cache): {} keys, {} misses, {} put after the misses",
+                         cold.len(), cold_misses, format_bytes(cold_bytes));
+        let mut cold: Vec<(&&Key, &(u64, u64))> = cold.iter().collect();
+        cold.sort_by_key(|(_, (misses, bytes))| std::cmp::Reverse((*misses, *bytes)));
+        for (key, (misses, bytes)) in cold.into_iter().take(top) {
+            let _ = writeln!(out, "  {} {:>6} misses {:>12}", format_key(key), misses, format_bytes(*bytes));
+        }
+
+        let mut unread: Vec<(&&Key, u64)> = puts.iter().filter(|(_, (_, read))| !read).map(|(key, (bytes, _))| (key, *bytes)).collect();
+        let unread_bytes: u64 = unread.iter().map(|(_, bytes)| bytes).sum();
+        let _ = writeln!(out, "\nkeys put and never read after: {} keys, {}", unread.len(), format_bytes(unread_bytes));
+        unread.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        for (key, bytes) in unread.into_iter().take(top) {
+            let _ = writeln!(out, "  {} {:>12}", format_key(key), format_bytes(bytes));
+        }
+
+        let _ = writeln!(out, "\nbytes by file type");
+        let _ = writeln!(out, "  {:<10} {:>10} {:>12} {:>10} {:>12}", "type", "hits", "sent", "puts", "received");
+        for t in FILE_TYPES {
+            let bytes = by_type.get(&t).copied().unwrap_or_default();
+            let _ = writeln!(out, "  {:<10} {:>10} {:>12} {:>10} {:>12}", t.to_ext(), bytes.hits, format_bytes(bytes.sent), bytes.puts, format_bytes(bytes.received));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Counts {
+    gets: u64,
+    hits: u64,
+}
+
+impl Counts {
+    fn add(&mut self, hit: bool) {
+        self.gets += 1;
+        if hit {
+            self.hits += 1;
+        }
+    }
+
+    fn hit_rate(&self) -> String {
+        if self.gets == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.1}%", self.hits as f64 * 100.0 / self.gets as f64)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct TypeBytes {
+    hits: u64,
+    sent: u64,
+    puts: u64,
+    received: u64,
+}
+
+/// the get or put of an entry. Some(None) for the other commands.
+/// rejected and discarded puts are not stored, so they are left out.
+fn parse_entry(entry: Entry) -> Option<Option<Event>> {
+    let action = match (entry.command.as_str(), entry.result.as_str()) {
+        ("get", "hit") => Action::Get(Some(true)),
+        ("get", _) => Action::Get(Some(false)),
+        ("put", "ok") => Action::Put,
+        _ => return Some(None),
+    };
+    let key = Key {
+        listener: entry.listener,
+        file_type: UnityFileType::try_from_ext(entry.file_type.as_deref()?).ok()?,
+        guid: HexString::from_hex_string(entry.guid?).ok()?,
+        hash: HexString::from_hex_string(entry.hash?).ok()?,
+    };
+    Some(Some(Event {
+        time: humantime::parse_rfc3339(&entry.timestamp).ok()?,
+        client: entry.client,
+        key,
+        action,
+        bytes: entry.bytes,
+    }))
+}
+
+/// the ip of a peer address, or the address itself, e.g. a unix socket
+fn client_ip(peer: &str) -> String {
+    match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    }
+}
+
+/// hours since the unix epoch
+fn hour(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 3600
+}
+
+fn format_key(key: &Key) -> String {
+    if key.listener.is_empty() {
+        format!("{}-{}.{}", key.guid, key.hash, key.file_type.to_ext())
+    } else {
+        format!("{}/{}-{}.{}", key.listener, key.guid, key.hash, key.file_type.to_ext())
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUID: &str = "0123456789abcdef0123456789abcdef";
+
+    fn hash(n: u8) -> String {
+        format!("{:032x}", n)
+    }
+
+    fn line(second: u64, command: &str, file_type: &str, hash: &str, result: &str, bytes: u64) -> String {
+        serde_json::json!({
+            "timestamp": format!("2024-01-02T03:04:{:02}.000000Z", second),
+            "listener": "main",
+            "client": "10.0.0.1",
+            "command": command,
+            "file_type": file_type,
+            "guid": GUID,
+            "hash": hash,
+            "result": result,
+            "bytes": bytes,
+            "duration_ms": 0.1,
+        }).to_string()
+    }
+
+    fn entry(line: &str) -> Entry {
+        serde_json::from_str(line).unwrap()
+    }
+
+    fn access_log(lines: &[String]) -> Report {
+        let mut report = Report::new();
+        report.add_access_log(lines.join("\n").as_bytes());
+        report
+    }
+
+    #[test]
+    fn gets_and_stored_puts_are_events() {
+        let event = parse_entry(entry(&line(1, "get", "bin", &hash(1), "hit", 10))).unwrap().unwrap();
+        assert!(matches!(event.action, Action::Get(Some(true))));
+        assert_eq!(event.key, Key { listener: "main".to_string(), file_type: UnityFileType::Asset, guid: HexString::from_hex_string(GUID.to_string()).unwrap(), hash: HexString::from_hex_string(hash(1)).unwrap() });
+        assert_eq!(event.client, "10.0.0.1");
+        assert_eq!(event.bytes, 10);
+        assert_eq!(event.time, humantime::parse_rfc3339("2024-01-02T03:04:01Z").unwrap());
+
+        let event = parse_entry(entry(&line(1, "get", "info", &hash(1), "miss", 0))).unwrap().unwrap();
+        assert!(matches!(event.action, Action::Get(Some(false))));
+        assert_eq!(event.key.file_type, UnityFileType::Info);
+        let event = parse_entry(entry(&line(1, "put", "resource", &hash(1), "ok", 20))).unwrap().unwrap();
+        assert!(matches!(event.action, Action::Put));
+        assert_eq!(event.bytes, 20);
+    }
+
+    #[test]
+    fn other_commands_and_unstored_puts_are_left_out() {
+        assert!(parse_entry(entry(&line(1, "put", "bin", &hash(1), "rejected", 20))).unwrap().is_none());
+        assert!(parse_entry(entry(&line(1, "start_transaction", "bin", &hash(1), "ok", 0))).unwrap().is_none());
+        assert!(parse_entry(entry(&line(1, "end_transaction", "bin", &hash(1), "ok", 0))).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_entries_are_skipped() {
+        assert!(parse_entry(entry(&line(1, "get", "exe", &hash(1), "hit", 0))).is_none());
+        assert!(parse_entry(entry(&line(1, "get", "bin", "xyz", "hit", 0))).is_none());
+        assert!(parse_entry(entry(&line(1, "get", "bin", &hash(1), "hit", 0).replace("2024-01-02T03:04:01.000000Z", "yesterday"))).is_none());
+
+        let report = access_log(&[
+            line(1, "get", "bin", &hash(1), "hit", 10),
+            String::new(),
+            "{\"timestamp\": \"2024-01-02T03".to_string(),
+            line(2, "get", "exe", &hash(1), "hit", 10),
+            line(3, "start_transaction", "bin", &hash(1), "ok", 0),
+        ]);
+        assert_eq!(report.events.len(), 1);
+        assert_eq!(report.skipped_lines, 2);
+        assert!(report.render(10).contains("skipped 2 invalid access log lines"));
+    }
+
+    #[test]
+    fn cold_misses_and_unread_puts() {
+        let report = access_log(&[
+            // missed twice, then put and read: cold, but read
+            line(1, "get", "bin", &hash(1), "miss", 0),
+            line(2, "get", "bin", &hash(1), "miss", 0),
+            line(3, "put", "bin", &hash(1), "ok", 100),
+            line(4, "get", "bin", &hash(1), "hit", 100),
+            // put and never read
+            line(5, "put", "info", &hash(2), "ok", 20),
+            // read, then put again and not read after
+            line(6, "put", "bin", &hash(3), "ok", 1),
+            line(7, "get", "bin", &hash(3), "hit", 1),
+            line(8, "put", "bin", &hash(3), "ok", 2),
+            // missed and never put
+            line(9, "get", "resource", &hash(4), "miss", 0),
+        ]);
+        let out = report.render(10);
+        assert!(out.contains("gets: 5, hits: 2, misses: 3, hit rate: 40.0%"), "{}", out);
+        assert!(out.contains("missed keys put later (cold cache): 1 keys, 2 misses, 100 B put after the misses"), "{}", out);
+        assert!(out.contains(&format!("main/{}-{}.bin      2 misses", GUID, hash(1))), "{}", out);
+        // the bytes of both puts of hash 3
+        assert!(out.contains("keys put and never read after: 2 keys, 23 B"), "{}", out);
+        assert!(out.contains(&format!("main/{}-{}.info", GUID, hash(2))), "{}", out);
+        assert!(!out.contains("replayed"), "{}", out);
+    }
+
+    async fn command(command: Command, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        command.write_to(&mut buf).await.unwrap();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// a recording of one chunk per command, a second apart
+    fn write_recording(path: &Path, commands: &[Vec<u8>]) {
+        let mut content = recorder::MAGIC.to_vec();
+        content.extend_from_slice(&1_700_000_000_000_000u64.to_le_bytes());
+        let peer = b"10.0.0.2:5000";
+        content.extend_from_slice(&(peer.len() as u16).to_le_bytes());
+        content.extend_from_slice(peer);
+        for (i, command) in commands.iter().enumerate() {
+            content.extend_from_slice(&(i as u64 * 1_000_000).to_le_bytes());
+            content.extend_from_slice(&(command.len() as u32).to_le_bytes());
+            content.extend_from_slice(command);
+        }
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recordings_are_replayed_against_an_empty_cache() {
+        let path = std::env::temp_dir().join(format!("ucs-report-test-{}.{}", uuid::Uuid::new_v4(), recorder::EXT));
+        let guid = HexString::from_hex_string(GUID.to_string()).unwrap();
+        let hash1 = HexString::from_hex_string(hash(1)).unwrap();
+        let get = command(Command::Get(UnityFileType::Asset, guid, hash1), b"").await;
+        write_recording(&path, &[
+            b"000000fe".to_vec(),
+            get.clone(),
+            command(Command::TransactionStart(guid, hash1), b"").await,
+            command(Command::Put(UnityFileType::Asset, 5), b"12345").await,
+            command(Command::TransactionEnd, b"").await,
+            get,
+            // a put outside of a transaction is not stored
+            command(Command::Put(UnityFileType::Info, 3), b"abc").await,
+            command(Command::Get(UnityFileType::Info, guid, hash1), b"").await,
+            command(Command::Quit, b"").await,
+        ]);
+        let mut report = Report::new();
+        report.add_file(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.events.len(), 4);
+        assert!(report.simulated);
+        assert_eq!(report.truncated_recordings, 0);
+        assert!(report.events.iter().all(|event| event.client == "10.0.0.2" && event.key.listener.is_empty()));
+        assert_eq!(report.events[1].time, UNIX_EPOCH + Duration::from_secs(1_700_000_004));
+        let out = report.render(10);
+        assert!(out.contains("gets: 3, hits: 1, misses: 2, hit rate: 33.3%"), "{}", out);
+        assert!(out.contains("replayed against a cache which starts empty"), "{}", out);
+        assert!(out.contains("missed keys put later (cold cache): 1 keys, 1 misses, 5 B put after the misses"), "{}", out);
+        assert!(out.contains("keys put and never read after: 0 keys, 0 B"), "{}", out);
+        assert!(out.contains(&format!("  {}-{}.bin ", GUID, hash(1))), "{}", out);
+    }
+}
@@ -1,10 +1,19 @@
-use std::io::ErrorKind;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
+use futures::stream::{FuturesOrdered, StreamExt};
 use tokio::io;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Take};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 
-use crate::{Error, HexString, Result, u32_to_be_hex_string, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::{Error, Result, UnityFileGuid, UnityFileHash, UnityFileType};
+use crate::mode::{Mode, ModeSwitch};
+use crate::protocol::{Command, PROTOCOL_VERSION, read_version, Response};
+use crate::shutdown::{ShutdownSignal, ShutdownState};
 
 #[async_trait]
 pub trait Handler: Sync {
@@ -12,7 +21,7 @@ pub trait Handler: Sync {
 
     /// verify version: only 254 allowed
     async fn version(&self, version: u32) -> Result<u32> {
-        if version == 254 {
+        if version == PROTOCOL_VERSION {
             Ok(version)
         } else {
             Err(Error::WrongVersion(version))
@@ -34,155 +43,1098 @@ pub trait Handler: Sync {
 
     /// put file: write file to temporary directory, calculate file hash.
     async fn put<R: AsyncRead + Unpin + Send>(&mut self, t: UnityFileType, size: u64, reader: R) -> Result<()>;
+
+    /// files and bytes stored. None if the backend can't tell.
+    /// may be slow, e.g. it walks the directory of a file system backend.
+    async fn storage_usage(&self) -> Result<Option<StorageUsage>> {
+        Ok(None)
+    }
+
+    /// remove what failed uploads left behind and is older than `older_than`, e.g. temp files after a crash.
+    /// return the number of removed files.
+    async fn cleanup(&self, _older_than: Duration) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+/// Space used by the cached files
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct StorageUsage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct HandleOptions {
+    /// Max number of get lookups running at the same time
+    /// 1 for serving gets one by one
+    pipeline_depth: usize,
+    /// Max time to receive the version from a new client
+    handshake_timeout: Option<Duration>,
+    /// Max time to wait for the next command
+    idle_timeout: Option<Duration>,
+    /// Max time from transaction start to transaction end
+    /// The transaction is cancelled when it expires
+    transaction_timeout: Option<Duration>,
+    /// Min speed of a file transfer in bytes per second
+    /// 0 for no limit
+    min_transfer_rate: u64,
+    /// Max size of an uploaded file. Larger puts are rejected before they reach the handler.
+    /// 0 for no limit
+    max_file_size: u64,
+    /// Server shutdown. Draining closes the session once it has no open transaction.
+    /// Terminating cancels the open transaction and closes the session.
+    shutdown: Option<ShutdownSignal>,
+    /// Closes this session only, like a terminating shutdown. Used to force-close a stuck client.
+    close: Option<ShutdownSignal>,
+    /// Whether the client may upload files
+    /// If not, puts are drained and discarded and transactions are ignored
+    put_allowed: bool,
+    /// Runtime mode shared with the server
+    mode: ModeSwitch,
+    /// Receive a record of every served command
+    observers: Vec<Arc<dyn CommandObserver>>,
+}
+
+impl Default for HandleOptions {
+    fn default() -> Self {
+        Self {
+            pipeline_depth: Self::DEFAULT_PIPELINE_DEPTH,
+            handshake_timeout: None,
+            idle_timeout: None,
+            transaction_timeout: None,
+            min_transfer_rate: 0,
+            max_file_size: 0,
+            shutdown: None,
+            close: None,
+            put_allowed: true,
+            mode: ModeSwitch::default(),
+            observers: Vec::new(),
+        }
+    }
+}
+
+impl HandleOptions {
+    /// Get lookups running at the same time, unless configured
+    pub const DEFAULT_PIPELINE_DEPTH: usize = 16;
+
+    /// Time every file transfer is allowed on top of what `min_transfer_rate` gives its size
+    pub const TRANSFER_GRACE_TIME: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    pub fn set_pipeline_depth(&mut self, pipeline_depth: usize) {
+        self.pipeline_depth = pipeline_depth.max(1);
+    }
+
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Option<Duration>) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn transaction_timeout(&self) -> Option<Duration> {
+        self.transaction_timeout
+    }
+
+    pub fn set_transaction_timeout(&mut self, transaction_timeout: Option<Duration>) {
+        self.transaction_timeout = transaction_timeout;
+    }
+
+    pub fn min_transfer_rate(&self) -> u64 {
+        self.min_transfer_rate
+    }
+
+    pub fn set_min_transfer_rate(&mut self, min_transfer_rate: u64) {
+        self.min_transfer_rate = min_transfer_rate;
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
+
+    pub fn shutdown(&self) -> Option<&ShutdownSignal> {
+        self.shutdown.as_ref()
+    }
+
+    pub fn set_shutdown(&mut self, shutdown: Option<ShutdownSignal>) {
+        self.shutdown = shutdown;
+    }
+
+    pub fn close(&self) -> Option<&ShutdownSignal> {
+        self.close.as_ref()
+    }
+
+    pub fn set_close(&mut self, close: Option<ShutdownSignal>) {
+        self.close = close;
+    }
+
+    pub fn put_allowed(&self) -> bool {
+        self.put_allowed
+    }
+
+    pub fn set_put_allowed(&mut self, put_allowed: bool) {
+        self.put_allowed = put_allowed;
+    }
+
+    pub fn mode(&self) -> &ModeSwitch {
+        &self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ModeSwitch) {
+        self.mode = mode;
+    }
+
+    pub fn observers(&self) -> &[Arc<dyn CommandObserver>] {
+        &self.observers
+    }
+
+    pub fn add_observer(&mut self, observer: Arc<dyn CommandObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Max time to transfer a file of `size` bytes
+    pub fn transfer_timeout(&self, size: u64) -> Option<Duration> {
+        if self.min_transfer_rate == 0 {
+            None
+        } else {
+            Some(Self::TRANSFER_GRACE_TIME + Duration::from_secs_f64(size as f64 / self.min_transfer_rate as f64))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CommandKind {
+    Get,
+    StartTransaction,
+    EndTransaction,
+    Put,
 }
 
-pub async fn handle<R, W, H>(reader: &mut R, writer: &mut W, mut handler: H) -> Result<()>
+impl CommandKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandKind::Get => "get",
+            CommandKind::StartTransaction => "start_transaction",
+            CommandKind::EndTransaction => "end_transaction",
+            CommandKind::Put => "put",
+        }
+    }
+}
+
+/// What became of a command
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CommandOutcome {
+    /// get: the file is sent
+    Hit,
+    /// get: the file is not found
+    Miss,
+    /// the transaction is started or committed, or the file is received
+    Ok,
+    /// the handler failed the command with the named [`Error`]. a rejected get is answered as a miss.
+    Rejected(&'static str),
+    /// ignored because the client may not upload or the server is read-only
+    Discarded,
+}
+
+impl CommandOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandOutcome::Hit => "hit",
+            CommandOutcome::Miss => "miss",
+            CommandOutcome::Ok => "ok",
+            CommandOutcome::Rejected(_) => "rejected",
+            CommandOutcome::Discarded => "discarded",
+        }
+    }
+
+    /// the name of the error which rejected the command
+    pub fn error(&self) -> Option<&'static str> {
+        match self {
+            CommandOutcome::Rejected(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A served command
+#[derive(Debug, Clone)]
+pub struct CommandRecord {
+    pub kind: CommandKind,
+    pub file_type: Option<UnityFileType>,
+    /// the key of the get, or of the transaction
+    pub guid: Option<UnityFileGuid>,
+    pub hash: Option<UnityFileHash>,
+    pub outcome: CommandOutcome,
+    /// file bytes sent or received
+    pub bytes: u64,
+    pub started_at: SystemTime,
+    /// until the response is sent or the file is received
+    pub duration: Duration,
+    /// time spent in the handler. a put includes receiving the file. None if the handler is not called.
+    pub handler_time: Option<Duration>,
+}
+
+/// Receives a record of every served command, e.g. to write an access log
+pub trait CommandObserver: Send + Sync + Debug {
+    /// a command is read. every start is followed by one `on_command`, unless the session ends.
+    fn on_command_started(&self, _kind: CommandKind) {}
+
+    fn on_command(&self, record: CommandRecord);
+
+    /// a transaction is started. it is closed by exactly one `on_transaction_closed`.
+    fn on_transaction_opened(&self) {}
+
+    /// the transaction is committed, or cancelled if `committed` is false
+    fn on_transaction_closed(&self, _committed: bool) {}
+}
+
+/// when a command started
+#[derive(Debug, Copy, Clone)]
+struct Started {
+    at: SystemTime,
+    instant: Instant,
+}
+
+impl Started {
+    fn now() -> Self {
+        Self {
+            at: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// a record of the command finished now, without file type, bytes and handler time
+    fn record(&self, kind: CommandKind, key: Option<(UnityFileGuid, UnityFileHash)>, outcome: CommandOutcome) -> CommandRecord {
+        CommandRecord {
+            kind,
+            file_type: None,
+            guid: key.map(|(guid, _)| guid),
+            hash: key.map(|(_, hash)| hash),
+            outcome,
+            bytes: 0,
+            started_at: self.at,
+            duration: self.instant.elapsed(),
+            handler_time: None,
+        }
+    }
+}
+
+/// a get lookup in the pipeline
+struct Lookup<F> {
+    started: Started,
+    handler_time: Option<Duration>,
+    t: UnityFileType,
+    guid: UnityFileGuid,
+    hash: UnityFileHash,
+    result: Result<Option<(u64, F)>>,
+}
+
+/// Per connection state
+struct Session<'a, H> {
+    handler: H,
+    options: &'a HandleOptions,
+    /// when the open transaction expires. None if there is no transaction or it never expires.
+    transaction_deadline: Option<Instant>,
+    /// guid and hash of the open transaction
+    transaction: Option<(UnityFileGuid, UnityFileHash)>,
+}
+
+impl<'a, H: Handler> Session<'a, H> {
+    fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Ok(Rejected) if the handler rejects the transaction
+    async fn start_transaction(&mut self, guid: UnityFileGuid, hash: UnityFileHash) -> Result<CommandOutcome> {
+        // the handler replaces an open transaction
+        self.close_transaction(false);
+        let result = recover(self.handler.start_transaction(guid, hash).await, "start_transaction")?;
+        if result.is_ok() {
+            self.transaction = Some((guid, hash));
+            self.transaction_deadline = self.options.transaction_timeout.map(|t| Instant::now() + t);
+            for observer in self.options.observers.iter() {
+                observer.on_transaction_opened();
+            }
+        }
+        Ok(outcome(result))
+    }
+
+    /// Ok(Rejected) if the handler fails to commit the transaction
+    async fn end_transaction(&mut self) -> Result<CommandOutcome> {
+        let result = recover(self.handler.end_transaction().await, "end_transaction");
+        self.close_transaction(matches!(result, Ok(Ok(_))));
+        Ok(outcome(result?))
+    }
+
+    async fn cancel_transaction(&mut self) -> Result<()> {
+        self.close_transaction(false);
+        let _ = recover(self.handler.cancel_transaction().await, "cancel_transaction")?;
+        Ok(())
+    }
+
+    fn close_transaction(&mut self, committed: bool) {
+        self.transaction_deadline = None;
+        if self.transaction.take().is_some() {
+            for observer in self.options.observers.iter() {
+                observer.on_transaction_closed(committed);
+            }
+        }
+    }
+
+    fn command_started(&self, kind: CommandKind) {
+        for observer in self.options.observers.iter() {
+            observer.on_command_started(kind);
+        }
+    }
+
+    /// report a served command to the observers
+    fn observe(&self, record: CommandRecord) {
+        for observer in self.options.observers.iter() {
+            observer.on_command(record.clone());
+        }
+    }
+
+    /// why puts of this session are discarded. None if they are stored.
+    fn put_rejection(&self) -> Option<&'static str> {
+        if !self.options.put_allowed {
+            Some("puts are not allowed")
+        } else if self.options.mode.get() == Mode::ReadOnly {
+            Some("server is read-only")
+        } else {
+            None
+        }
+    }
+
+    /// cancel the transaction if it has expired
+    async fn expire_transaction(&mut self) -> Result<()> {
+        if matches!(self.transaction_deadline, Some(deadline) if deadline <= Instant::now()) {
+            warn!("transaction timeout");
+            self.cancel_transaction().await?;
+        }
+        Ok(())
+    }
+
+    /// wait until the server shutdown reaches `state`
+    async fn wait_shutdown(&self, state: ShutdownState) {
+        wait_signal(self.options.shutdown.as_ref(), state).await
+    }
+
+    /// wait for the next command
+    /// an expired transaction is cancelled while waiting
+    async fn read_command<R>(&mut self, reader: &mut R) -> Result<Option<Command>>
+        where
+            R: AsyncRead + Unpin + Send,
+    {
+        let idle_deadline = self.options.idle_timeout.map(|t| Instant::now() + t);
+        let read = Command::read_from(reader);
+        tokio::pin!(read);
+        loop {
+            let transaction_deadline = self.transaction_deadline;
+            tokio::select! {
+                result = &mut read => return result,
+                _ = sleep_until(transaction_deadline) => self.expire_transaction().await?,
+                _ = sleep_until(idle_deadline) => return Err(Error::Timeout("idle")),
+                _ = self.wait_shutdown(ShutdownState::Draining), if !self.in_transaction() => return Ok(None),
+            }
+        }
+    }
+}
+
+pub async fn handle<R, W, H>(reader: &mut R, writer: &mut W, handler: H) -> Result<()>
     where
         R: AsyncRead + Unpin + Send,
         W: AsyncWrite + Unpin + ?Sized,
         H: Handler,
 {
-    let version = read_version(&mut *reader).await?;
+    handle_with_options(reader, writer, handler, &HandleOptions::default()).await
+}
+
+pub async fn handle_with_options<R, W, H>(reader: &mut R, writer: &mut W, handler: H, options: &HandleOptions) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin + ?Sized,
+        H: Handler,
+{
+    let version = with_timeout(options.handshake_timeout, "handshake", read_version(&mut *reader)).await?;
     let response_version = handler.version(version).await?;
-    let _ = writer.write(u32_to_be_hex_string(response_version).as_ref()).await?;
+    Response::Version(response_version).write_to(writer).await?;
     writer.flush().await?;
 
+    let mut session = Session {
+        handler,
+        options,
+        transaction_deadline: None,
+        transaction: None,
+    };
+    let result = tokio::select! {
+        result = serve(reader, writer, &mut session) => result,
+        _ = wait_signal(options.shutdown.as_ref(), ShutdownState::Terminating) => {
+            info!("session terminated by shutdown");
+            Ok(())
+        }
+        _ = wait_signal(options.close.as_ref(), ShutdownState::Terminating) => {
+            info!("session closed");
+            Ok(())
+        }
+    };
+    if session.in_transaction() {
+        // the client is gone. don't leave the temporary files behind.
+        if let Err(e) = session.cancel_transaction().await {
+            // keep the error which ended the session
+            if result.is_err() {
+                warn!(error = %e, "cancel transaction error");
+            } else {
+                return Err(e);
+            }
+        }
+    }
+    result
+}
+
+async fn serve<R, W, H>(reader: &mut R, writer: &mut W, session: &mut Session<'_, H>) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin + ?Sized,
+        H: Handler,
+{
+    let mut next = session.read_command(&mut *reader).await?;
+    while let Some(command) = next.take() {
+        session.expire_transaction().await?;
+        match command {
+            Command::Get(t, guid, hash) => {
+                next = serve_gets(&mut *reader, writer, session, (t, guid, hash)).await?;
+                continue;
+            }
+            Command::TransactionStart(guid, hash) => {
+                debug!(%guid, %hash, "start_transaction");
+                session.command_started(CommandKind::StartTransaction);
+                let started = Started::now();
+                let mut handler_time = None;
+                // the client is not told about it. the transaction just never starts.
+                let outcome = if session.put_rejection().is_some() {
+                    CommandOutcome::Discarded
+                } else {
+                    let outcome = session.start_transaction(guid, hash).await?;
+                    handler_time = Some(started.instant.elapsed());
+                    outcome
+                };
+                session.observe(CommandRecord {
+                    handler_time,
+                    ..started.record(CommandKind::StartTransaction, Some((guid, hash)), outcome)
+                });
+            }
+            Command::TransactionEnd => {
+                debug!("end_transaction");
+                session.command_started(CommandKind::EndTransaction);
+                let started = Started::now();
+                let transaction = session.transaction;
+                let mut handler_time = None;
+                let outcome = match session.put_rejection() {
+                    None => {
+                        let outcome = session.end_transaction().await?;
+                        handler_time = Some(started.instant.elapsed());
+                        outcome
+                    }
+                    // switched to read-only during the transaction
+                    Some(reason) if session.in_transaction() => {
+                        info!(reason, "end_transaction cancelled");
+                        session.cancel_transaction().await?;
+                        CommandOutcome::Discarded
+                    }
+                    Some(_) => CommandOutcome::Discarded,
+                };
+                session.observe(CommandRecord {
+                    handler_time,
+                    ..started.record(CommandKind::EndTransaction, transaction, outcome)
+                });
+            }
+            Command::Put(t, size) => {
+                serve_put(&mut *reader, session, t, size).await?;
+            }
+            Command::Quit => {
+                break;
+            }
+        }
+        next = session.read_command(&mut *reader).await?;
+    }
+    Ok(())
+}
+
+/// receive a file. the payload is drained if the session may not upload or the handler rejects it.
+async fn serve_put<R, H>(reader: &mut R, session: &mut Session<'_, H>, t: UnityFileType, size: u64) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+        H: Handler,
+{
+    let started = Started::now();
+    session.command_started(CommandKind::Put);
+    if let Some(reason) = session.put_rejection() {
+        debug!(file_type = t.to_ext(), size, reason, "put discarded");
+        with_timeout(session.options.transfer_timeout(size), "transfer", drain(&mut reader.take(size))).await?;
+        session.observe(CommandRecord {
+            file_type: Some(t),
+            bytes: size,
+            ..started.record(CommandKind::Put, session.transaction, CommandOutcome::Discarded)
+        });
+        return Ok(());
+    }
+    debug!(file_type = t.to_ext(), size, "put");
+    let mut handler_time = None;
+    let result = with_timeout(session.options.transfer_timeout(size), "transfer", async {
+        let mut payload = (&mut *reader).take(size);
+        let max_file_size = session.options.max_file_size;
+        let result = if max_file_size != 0 && size > max_file_size {
+            Err(Error::FileTooLarge {
+                max_size: max_file_size as usize,
+                size: size as usize,
+            })
+        } else {
+            let handler_started = Instant::now();
+            let result = session.handler.put(t, size, &mut payload).await;
+            handler_time = Some(handler_started.elapsed());
+            result
+        };
+        let result = recover(result, "put")?;
+        // the handler may reject the file before reading all of it
+        let remaining = payload.limit();
+        if remaining != 0 {
+            debug!(file_type = t.to_ext(), remaining, "put discard the rest of the file");
+            drain(&mut payload).await?;
+        }
+        Ok(result)
+    }).await?;
+    let accepted = result.is_ok();
+    session.observe(CommandRecord {
+        file_type: Some(t),
+        bytes: size,
+        handler_time,
+        ..started.record(CommandKind::Put, session.transaction, outcome(result))
+    });
+    if !accepted && session.in_transaction() {
+        // the transaction lost one of its files. don't commit the rest.
+        session.cancel_transaction().await?;
+    }
+    Ok(())
+}
+
+/// serve a run of consecutive gets.
+/// up to `pipeline_depth` lookups run concurrently while the following commands are read ahead.
+/// responses are written in request order.
+/// when the transaction expires, reading ahead stops until the pending lookups are answered,
+/// then the transaction is cancelled and the gets go on.
+/// return the first command which is not a get, None if the stream ends.
+async fn serve_gets<R, W, H>(reader: &mut R, writer: &mut W, session: &mut Session<'_, H>, first: (UnityFileType, UnityFileGuid, UnityFileHash)) -> Result<Option<Command>>
+    where
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin + ?Sized,
+        H: Handler,
+{
+    let options = session.options;
+    let mut first = Some(first);
+    let mut reading = Some(Box::pin(read_command(reader)));
+    let mut next = None;
+    // the client is only idle when it has all its responses
+    let mut idle_deadline = None;
+
     loop {
-        match reader.read_u8().await {
-            Err(e) => {
-                if e.kind() != ErrorKind::UnexpectedEof {
-                    return Err(Error::IoError(e));
+        let session_ref = &*session;
+        let handler = &session_ref.handler;
+        let lookup = |(t, guid, hash): (UnityFileType, UnityFileGuid, UnityFileHash)| {
+            debug!(file_type = t.to_ext(), %guid, %hash, "get");
+            session_ref.command_started(CommandKind::Get);
+            let pass_through_miss = options.mode.get() == Mode::PassThroughMiss;
+            let started = Started::now();
+            async move {
+                if pass_through_miss {
+                    return Lookup { started, handler_time: None, t, guid, hash, result: Ok(None) };
                 }
+                let result = handler.get(t, &guid, &hash).await;
+                Lookup { started, handler_time: Some(started.instant.elapsed()), t, guid, hash, result }
+            }
+        };
+        let mut lookups = FuturesOrdered::new();
+        if let Some(first) = first.take() {
+            lookups.push_back(lookup(first));
+        }
+        let transaction_deadline = session_ref.transaction_deadline;
+        let mut expired = false;
+
+        loop {
+            if expired && lookups.is_empty() {
                 break;
             }
-            Ok(b) => match b {
-                b'g' => {
-                    let b2 = reader.read_u8().await?;
-                    match UnityFileType::try_from_ext_char(b2) {
-                        Ok(t) => {
-                            let guid: UnityFileGuid = read_hex_string(&mut *reader).await?;
-                            let hash: UnityFileHash = read_hex_string(&mut *reader).await?;
-                            println!("get {} {} {}", t.to_ext(), guid.to_hex_string(), hash.to_hex_string());
-                            match handler.get(t, &guid, &hash).await? {
-                                None => {
-                                    let _ = writer.write_u8(b'-').await?;
-                                    let _ = writer.write_u8(t.to_ext_char()).await?;
-                                    let _ = writer.write(guid.as_ref()).await?;
-                                    let _ = writer.write(hash.as_ref()).await?;
-                                    writer.flush().await?;
-                                }
-                                Some((size, mut r)) => {
-                                    let _ = writer.write_u8(b'+').await?;
-                                    let _ = writer.write_u8(t.to_ext_char()).await?;
-                                    let _ = write_size_string(writer, size).await?;
-                                    let _ = writer.write(guid.as_ref()).await?;
-                                    let _ = writer.write(hash.as_ref()).await?;
-                                    let _ = io::copy(&mut r, writer).await?;
-                                    writer.flush().await?;
-                                }
-                            }
+            tokio::select! {
+                biased;
+                Some(Lookup { started, handler_time, t, guid, hash, result }) = lookups.next(), if !lookups.is_empty() => {
+                    let file = recover(result, "get")?;
+                    let outcome = match &file {
+                        Ok(Some(_)) => CommandOutcome::Hit,
+                        Ok(None) => CommandOutcome::Miss,
+                        Err(error) => CommandOutcome::Rejected(error),
+                    };
+                    let mut bytes = 0;
+                    match file.ok().flatten() {
+                        None => {
+                            Response::Miss(t, guid, hash).write_to(writer).await?;
+                            writer.flush().await?;
                         }
-                        Err(_) => {
-                            return Err(Error::UnknownFileTypeByte(b2));
+                        Some((size, mut r)) => {
+                            with_timeout(options.transfer_timeout(size), "transfer", async {
+                                Response::Hit(t, size, guid, hash).write_to(writer).await?;
+                                io::copy(&mut r, writer).await?;
+                                writer.flush().await?;
+                                Ok(())
+                            }).await?;
+                            bytes = size;
                         }
                     }
-                }
-                b't' => {
-                    match reader.read_u8().await? {
-                        b's' => {
-                            let guid: UnityFileGuid = read_hex_string(&mut *reader).await?;
-                            let hash: UnityFileHash = read_hex_string(&mut *reader).await?;
-                            println!("start_transaction {} {}", guid.to_hex_string(), hash.to_hex_string());
-                            handler.start_transaction(guid, hash).await?;
-                        }
-                        b'e' => {
-                            println!("end_transaction");
-                            handler.end_transaction().await?;
-                        }
-                        b => {
-                            return Err(Error::UnknownTransactionCommand(b));
-                        }
+                    session_ref.observe(CommandRecord {
+                        file_type: Some(t),
+                        bytes,
+                        handler_time,
+                        ..started.record(CommandKind::Get, Some((guid, hash)), outcome)
+                    });
+                    if lookups.is_empty() {
+                        idle_deadline = options.idle_timeout.map(|t| Instant::now() + t);
                     }
                 }
-                b'p' => {
-                    match reader.read_u8().await? {
-                        b @ (b'a' | b'i' | b'r') => {
-                            let t = UnityFileType::try_from_ext_char(b).expect("parse unity file type error");
-                            let size = read_size_string(&mut *reader).await?;
-                            println!("put {} {}", t.to_ext(), size);
-                            handler.put(t, size, &mut *reader).await?;
+                _ = sleep_until(transaction_deadline), if reading.is_some() && !expired => {
+                    expired = true;
+                }
+                (reader, result) = async { reading.as_mut().unwrap().await }, if reading.is_some() && !expired && lookups.len() < options.pipeline_depth => {
+                    match result? {
+                        Some(Command::Get(t, guid, hash)) => {
+                            lookups.push_back(lookup((t, guid, hash)));
+                            reading = Some(Box::pin(read_command(reader)));
+                            idle_deadline = None;
                         }
-                        b => {
-                            return Err(Error::UnknownPushCommand(b));
+                        command => {
+                            next = command;
+                            reading = None;
                         }
                     }
                 }
-                b'q' => {
-                    break;
+                _ = sleep_until(idle_deadline), if reading.is_some() && lookups.is_empty() => {
+                    return Err(Error::Timeout("idle"));
                 }
-                b => {
-                    return Err(Error::UnknownCommand(b));
+                _ = session_ref.wait_shutdown(ShutdownState::Draining), if reading.is_some() && lookups.is_empty() && !session_ref.in_transaction() => {
+                    return Ok(next);
                 }
+                else => return Ok(next),
             }
         }
+        drop(lookups);
+        session.expire_transaction().await?;
     }
-    Ok(())
 }
 
-pub async fn read_version<R>(reader: &mut R) -> Result<u32>
+/// the read future takes the reader and gives it back when a command is read,
+/// so it can be kept across loop iterations without being cancelled.
+async fn read_command<R>(reader: &mut R) -> (&mut R, Result<Option<Command>>)
     where
-        R: AsyncRead + Unpin
+        R: AsyncRead + Unpin + Send,
 {
-    let mut buf = vec![0u8; 8];
-    let n = reader.read(&mut buf).await?;
-    if n == 0 {
-        return Err(Error::ReadVersionError);
-    }
+    let result = Command::read_from(&mut *reader).await;
+    (reader, result)
+}
 
-    let n = if n == 1 {
-        let n2 = reader.read(&mut buf[n..8]).await?;
-        if n2 == 0 {
-            return Err(Error::ReadVersionError);
-        }
-        if n + n2 > 8 {
-            unreachable!("The buffer is 8 bytes len. So it cannot read more than 8 bytes");
-        }
-        n + n2
-    } else {
-        n
-    };
+/// wait until the signal reaches `state`. never return without a signal.
+async fn wait_signal(signal: Option<&ShutdownSignal>, state: ShutdownState) {
+    match signal {
+        Some(signal) => signal.wait(state).await,
+        None => std::future::pending().await,
+    }
+}
 
-    Ok(u32::from_str_radix(std::str::from_utf8(&buf[0..n])?, 16)?)
+/// sleep until the deadline. never wake up if there is no deadline.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
 }
 
-pub async fn read_hex_string<R, const N: usize>(reader: &mut R) -> Result<HexString<N>>
+/// fail with `Error::Timeout(stage)` if the future does not complete in time
+async fn with_timeout<T, F>(timeout: Option<Duration>, stage: &'static str, future: F) -> Result<T>
     where
-        R: AsyncRead + Unpin
+        F: Future<Output=Result<T>>,
 {
-    let mut s = HexString::new();
-    reader.read_exact(&mut s.0).await?;
-    Ok(s)
+    match timeout {
+        None => future.await,
+        Some(timeout) => tokio::time::timeout(timeout, future).await.unwrap_or(Err(Error::Timeout(stage))),
+    }
 }
 
-pub async fn read_size_string<R>(reader: &mut R) -> Result<u64>
-    where
-        R: AsyncRead + Unpin
-{
-    const U64_STRING_LENGTH: usize = 16;
+/// log and swallow a recoverable error. return other errors.
+/// Ok(Err(name of the error)) if the error is swallowed.
+fn recover<T>(result: Result<T>, command: &str) -> Result<std::result::Result<T, &'static str>> {
+    match result {
+        Ok(v) => Ok(Ok(v)),
+        Err(e) if e.is_recoverable() => {
+            warn!(command, error = %e, "command rejected");
+            Ok(Err(e.name()))
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    let mut s = String::new();
-    let n = reader.take(U64_STRING_LENGTH as u64).read_to_string(&mut s).await?;
-    if n != U64_STRING_LENGTH {
-        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+/// Ok, or Rejected by a recovered error
+fn outcome<T>(result: std::result::Result<T, &'static str>) -> CommandOutcome {
+    match result {
+        Ok(_) => CommandOutcome::Ok,
+        Err(error) => CommandOutcome::Rejected(error),
     }
-    Ok(u64::from_str_radix(&s, 16)?)
 }
 
-pub async fn write_size_string<W>(writer: &mut W, v: u64) -> Result<()>
+/// skip the rest of a payload
+async fn drain<R>(payload: &mut Take<R>) -> Result<()>
     where
-        W: AsyncWrite + Unpin + ?Sized
+        R: AsyncRead + Unpin,
 {
-    writer.write(format!("{:016x}", v).as_bytes()).await?;
+    let remaining = payload.limit();
+    let n = io::copy(payload, &mut io::sink()).await?;
+    if n != remaining {
+        return Err(Error::IoError(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+    }
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use crate::HexString;
+    use crate::handlers::{FileSystemHandler, MemoryHandler};
+    use crate::shutdown::Shutdown;
+
+    use super::*;
+
+    fn key(n: u8) -> (UnityFileGuid, UnityFileHash) {
+        (HexString([n; 16]), HexString([n; 16]))
+    }
+
+    /// gets wait for the delay given to their guid, then hit with the guid byte as content
+    #[derive(Debug, Clone, Default)]
+    struct DelayHandler {
+        delays: Arc<Mutex<Vec<(u8, Duration)>>>,
+        finished: Arc<Mutex<Vec<u8>>>,
+        cancelled: Arc<Mutex<usize>>,
+        fail_cancel: bool,
+    }
+
+    #[async_trait]
+    impl Handler for DelayHandler {
+        type File = Cursor<Vec<u8>>;
+
+        async fn get(&self, _t: UnityFileType, guid: &UnityFileGuid, _hash: &UnityFileHash) -> Result<Option<(u64, Self::File)>> {
+            let n = guid.0[0];
+            let delay = self.delays.lock().unwrap().iter().find(|(k, _)| *k == n).map(|(_, d)| *d).unwrap_or_default();
+            tokio::time::sleep(delay).await;
+            self.finished.lock().unwrap().push(n);
+            Ok(Some((1, Cursor::new(vec![n]))))
+        }
+
+        async fn start_transaction(&mut self, _guid: UnityFileGuid, _hash: UnityFileHash) -> Result<()> {
+            Ok(())
+        }
+
+        async fn end_transaction(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn cancel_transaction(&mut self) -> Result<()> {
+            *self.cancelled.lock().unwrap() += 1;
+            if self.fail_cancel {
+                return Err(Error::IoError(std::io::Error::other("cancel failed")));
+            }
+            Ok(())
+        }
+
+        async fn put<R: AsyncRead + Unpin + Send>(&mut self, _t: UnityFileType, size: u64, reader: R) -> Result<()> {
+            io::copy(&mut reader.take(size), &mut io::sink()).await?;
+            Ok(())
+        }
+    }
+
+    /// the client end of a session served with `options`
+    async fn connect<H>(handler: H, options: HandleOptions) -> (DuplexStream, tokio::task::JoinHandle<Result<()>>)
+        where
+            H: Handler + Send + 'static,
+            H::File: Send,
+    {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let session = tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(server);
+            handle_with_options(&mut reader, &mut writer, handler, &options).await
+        });
+        Response::Version(PROTOCOL_VERSION).write_to(&mut client).await.unwrap();
+        assert_eq!(Response::read_version_from(&mut client).await.unwrap(), Response::Version(PROTOCOL_VERSION));
+        (client, session)
+    }
+
+    async fn send(client: &mut DuplexStream, commands: &[Command]) {
+        for command in commands {
+            command.write_to(client).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_keep_request_order() {
+        let handler = DelayHandler::default();
+        // the first get finishes last
+        *handler.delays.lock().unwrap() = vec![(1, Duration::from_millis(200)), (2, Duration::from_millis(50))];
+        let (mut client, session) = connect(handler.clone(), HandleOptions::new()).await;
+        let gets: Vec<Command> = (1..=3).map(|n| Command::Get(UnityFileType::Asset, key(n).0, key(n).1)).collect();
+        send(&mut client, &gets).await;
+        send(&mut client, &[Command::Quit]).await;
+        for n in 1..=3 {
+            assert_eq!(Response::read_from(&mut client).await.unwrap(), Response::Hit(UnityFileType::Asset, 1, key(n).0, key(n).1));
+            assert_eq!(client.read_u8().await.unwrap(), n);
+        }
+        session.await.unwrap().unwrap();
+        assert_eq!(*handler.finished.lock().unwrap(), vec![3, 2, 1]);
+    }
+
+    #[tokio::test]
+    async fn pipeline_depth_one_serves_gets_one_by_one() {
+        let handler = DelayHandler::default();
+        *handler.delays.lock().unwrap() = vec![(1, Duration::from_millis(100))];
+        let mut options = HandleOptions::new();
+        options.set_pipeline_depth(1);
+        let (mut client, session) = connect(handler.clone(), options).await;
+        send(&mut client, &[Command::Get(UnityFileType::Asset, key(1).0, key(1).1), Command::Get(UnityFileType::Asset, key(2).0, key(2).1), Command::Quit]).await;
+        let mut responses = vec![0u8; 2 * (2 + 16 + 32 + 1)];
+        client.read_exact(&mut responses).await.unwrap();
+        session.await.unwrap().unwrap();
+        assert_eq!(*handler.finished.lock().unwrap(), vec![1, 2]);
+    }
+
+    async fn put_transaction(client: &mut DuplexStream, n: u8, files: &[(UnityFileType, &[u8])]) {
+        send(client, &[Command::TransactionStart(key(n).0, key(n).1)]).await;
+        for (t, content) in files {
+            send(client, &[Command::Put(*t, content.len() as u64)]).await;
+            client.write_all(content).await.unwrap();
+        }
+        send(client, &[Command::TransactionEnd]).await;
+    }
+
+    async fn get(client: &mut DuplexStream, t: UnityFileType, n: u8) -> Option<Vec<u8>> {
+        send(client, &[Command::Get(t, key(n).0, key(n).1)]).await;
+        match Response::read_from(client).await.unwrap() {
+            Response::Hit(_, size, _, _) => {
+                let mut content = vec![0u8; size as usize];
+                client.read_exact(&mut content).await.unwrap();
+                Some(content)
+            }
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_put_is_drained_and_the_session_continues() {
+        let mut options = HandleOptions::new();
+        options.set_max_file_size(8);
+        let (mut client, session) = connect(MemoryHandler::new(), options).await;
+        put_transaction(&mut client, 1, &[(UnityFileType::Asset, &[7u8; 100]), (UnityFileType::Info, b"info")]).await;
+        // the transaction lost a file, so none of its files is committed
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await, None);
+        assert_eq!(get(&mut client, UnityFileType::Info, 1).await, None);
+        put_transaction(&mut client, 2, &[(UnityFileType::Asset, b"small")]).await;
+        assert_eq!(get(&mut client, UnityFileType::Asset, 2).await.as_deref(), Some(&b"small"[..]));
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn put_rejected_by_the_handler_is_drained() {
+        let mut handler = MemoryHandler::new();
+        handler.set_max_file_size(8);
+        let (mut client, session) = connect(handler, HandleOptions::new()).await;
+        put_transaction(&mut client, 1, &[(UnityFileType::Resource, &[7u8; 100])]).await;
+        assert_eq!(get(&mut client, UnityFileType::Resource, 1).await, None);
+        put_transaction(&mut client, 2, &[(UnityFileType::Resource, b"ok")]).await;
+        assert_eq!(get(&mut client, UnityFileType::Resource, 2).await.as_deref(), Some(&b"ok"[..]));
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn put_outside_a_transaction_is_drained_and_the_session_continues() {
+        let (mut client, session) = connect(MemoryHandler::new(), HandleOptions::new()).await;
+        send(&mut client, &[Command::Put(UnityFileType::Asset, 3)]).await;
+        client.write_all(b"abc").await.unwrap();
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await, None);
+        put_transaction(&mut client, 1, &[(UnityFileType::Asset, b"abc")]).await;
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await.as_deref(), Some(&b"abc"[..]));
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn puts_are_discarded_when_not_allowed() {
+        let handler = MemoryHandler::new();
+        let (mut client, session) = connect(handler.clone(), HandleOptions::new()).await;
+        put_transaction(&mut client, 1, &[(UnityFileType::Asset, b"allowed")]).await;
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+
+        let mut options = HandleOptions::new();
+        options.set_put_allowed(false);
+        let (mut client, session) = connect(handler.clone(), options).await;
+        put_transaction(&mut client, 2, &[(UnityFileType::Asset, b"asset"), (UnityFileType::Info, b"info"), (UnityFileType::Resource, b"resource")]).await;
+        // an end without a start is not an error either
+        send(&mut client, &[Command::TransactionEnd]).await;
+        for t in [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource] {
+            assert_eq!(get(&mut client, t, 2).await, None);
+        }
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await.as_deref(), Some(&b"allowed"[..]));
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+        assert_eq!(handler.file_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_discards_puts_and_serves_gets() {
+        let handler = MemoryHandler::new();
+        let mode = ModeSwitch::default();
+        let mut options = HandleOptions::new();
+        options.set_mode(mode.clone());
+        let (mut client, session) = connect(handler.clone(), options).await;
+        put_transaction(&mut client, 1, &[(UnityFileType::Asset, b"stored")]).await;
+        // the reply shows the commands before it are done
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await.as_deref(), Some(&b"stored"[..]));
+
+        mode.set(Mode::ReadOnly);
+        put_transaction(&mut client, 2, &[(UnityFileType::Asset, b"discarded")]).await;
+        assert_eq!(get(&mut client, UnityFileType::Asset, 2).await, None);
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await.as_deref(), Some(&b"stored"[..]));
+
+        // switched during a transaction, which is then not committed
+        mode.set(Mode::Normal);
+        send(&mut client, &[Command::TransactionStart(key(3).0, key(3).1), Command::Put(UnityFileType::Asset, 4)]).await;
+        client.write_all(b"late").await.unwrap();
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await.as_deref(), Some(&b"stored"[..]));
+        mode.set(Mode::ReadOnly);
+        send(&mut client, &[Command::TransactionEnd]).await;
+        assert_eq!(get(&mut client, UnityFileType::Asset, 3).await, None);
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+        assert_eq!(handler.file_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn pass_through_miss_mode_misses_every_get_and_stores_puts() {
+        let handler = MemoryHandler::new();
+        let mut options = HandleOptions::new();
+        options.set_mode(ModeSwitch::new(Mode::PassThroughMiss));
+        let mode = options.mode().clone();
+        let (mut client, session) = connect(handler.clone(), options).await;
+        put_transaction(&mut client, 1, &[(UnityFileType::Asset, b"stored")]).await;
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await, None);
+
+        mode.set(Mode::Normal);
+        assert_eq!(get(&mut client, UnityFileType::Asset, 1).await.as_deref(), Some(&b"stored"[..]));
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+        assert_eq!(handler.file_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_command_ends_the_session() {
+        let (mut client, session) = connect(MemoryHandler::new(), HandleOptions::new()).await;
+        client.write_all(b"x").await.unwrap();
+        assert!(matches!(session.await.unwrap(), Err(Error::UnknownCommand(b'x'))));
+    }
+
+    #[tokio::test]
+    async fn transaction_expires_while_the_client_only_sends_gets() {
+        let handler = DelayHandler::default();
+        let mut options = HandleOptions::new();
+        options.set_transaction_timeout(Some(Duration::from_millis(100)));
+        let (mut client, session) = connect(handler.clone(), options).await;
+        send(&mut client, &[Command::TransactionStart(key(1).0, key(1).1)]).await;
+        for _ in 0..15 {
+            assert!(get(&mut client, UnityFileType::Asset, 2).await.is_some());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(*handler.cancelled.lock().unwrap(), 1);
+        send(&mut client, &[Command::Quit]).await;
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_error_does_not_replace_the_session_error() {
+        let handler = DelayHandler { fail_cancel: true, ..Default::default() };
+        let (mut client, session) = connect(handler.clone(), HandleOptions::new()).await;
+        send(&mut client, &[Command::TransactionStart(key(1).0, key(1).1)]).await;
+        client.write_all(b"x").await.unwrap();
+        assert!(matches!(session.await.unwrap(), Err(Error::UnknownCommand(b'x'))));
+        assert_eq!(*handler.cancelled.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn drain_closes_idle_sessions_and_finishes_open_transactions() {
+        let handler = MemoryHandler::new();
+        let shutdown = Shutdown::new();
+        let mut options = HandleOptions::new();
+        options.set_shutdown(Some(shutdown.signal()));
+        let (_idle, idle_session) = connect(handler.clone(), options.clone()).await;
+        let (mut client, mut session) = connect(handler.clone(), options).await;
+        send(&mut client, &[Command::TransactionStart(key(1).0, key(1).1)]).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.drain();
+        idle_session.await.unwrap().unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut session).await.is_err());
+        send(&mut client, &[Command::Put(UnityFileType::Asset, 3)]).await;
+        client.write_all(b"abc").await.unwrap();
+        send(&mut client, &[Command::TransactionEnd]).await;
+        session.await.unwrap().unwrap();
+        assert_eq!(handler.file_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn terminate_cancels_the_open_transaction_and_removes_its_temp_file() {
+        let dir = std::env::temp_dir().join(format!("ucs-serve-test-{}", uuid::Uuid::new_v4()));
+        let handler = FileSystemHandler::new(dir.join("data"), dir.join("temp"));
+        let shutdown = Shutdown::new();
+        let mut options = HandleOptions::new();
+        options.set_shutdown(Some(shutdown.signal()));
+        let (mut client, session) = connect(handler, options).await;
+        send(&mut client, &[Command::TransactionStart(key(1).0, key(1).1), Command::Put(UnityFileType::Asset, 3)]).await;
+        client.write_all(b"abc").await.unwrap();
+        let temp_files = || std::fs::read_dir(dir.join("temp")).map(|entries| entries.count()).unwrap_or(0);
+        while temp_files() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        shutdown.terminate();
+        session.await.unwrap().unwrap();
+        assert_eq!(temp_files(), 0);
+        assert!(!dir.join("data").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use tokio::sync::watch;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ShutdownState {
+    Running,
+    /// finish open transactions, then close the connections
+    Draining,
+    /// cancel open transactions and close the connections now
+    Terminating,
+}
+
+/// Server side of the shutdown: moves all the sessions through the shutdown states
+#[derive(Debug)]
+pub struct Shutdown {
+    sender: watch::Sender<ShutdownState>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(ShutdownState::Running);
+        Self {
+            sender,
+        }
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    pub fn state(&self) -> ShutdownState {
+        *self.sender.borrow()
+    }
+
+    pub fn drain(&self) {
+        self.set(ShutdownState::Draining);
+    }
+
+    pub fn terminate(&self) {
+        self.set(ShutdownState::Terminating);
+    }
+
+    fn set(&self, state: ShutdownState) {
+        self.sender.send_if_modified(|current| {
+            if *current < state {
+                *current = state;
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+/// Session side of the shutdown
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<ShutdownState>,
+}
+
+impl ShutdownSignal {
+    pub fn state(&self) -> ShutdownState {
+        *self.receiver.borrow()
+    }
+
+    /// wait until the shutdown reaches `state`
+    /// never return if the `Shutdown` is dropped before
+    pub async fn wait(&self, state: ShutdownState) {
+        let mut receiver = self.receiver.clone();
+        while *receiver.borrow_and_update() < state {
+            if receiver.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
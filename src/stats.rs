@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{CommandKind, CommandObserver, CommandOutcome, CommandRecord, StorageUsage, UnityFileType};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Connection and command counters of one listener
+#[derive(Debug, Default)]
+pub struct Stats {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    rejected: AtomicU64,
+    errors: AtomicU64,
+    /// by [`UnityFileType::to_u8`]
+    hits: [AtomicU64; UnityFileType::LENGTH],
+    misses: [AtomicU64; UnityFileType::LENGTH],
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// by the name of the error
+    put_rejections: Mutex<BTreeMap<&'static str, u64>>,
+    open_transactions: AtomicU64,
+    transactions_committed: AtomicU64,
+    transactions_cancelled: AtomicU64,
+    get_latency: Histogram,
+    put_latency: Histogram,
+    end_transaction_latency: Histogram,
+    /// last measured by the server
+    storage_usage: Mutex<Option<StorageUsage>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// number of connections accepted, including the rejected ones
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// number of connections being served
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// number of connections closed by the limits
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// number of sessions ended by an error
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// number of gets answered with the file
+    pub fn hits(&self, t: UnityFileType) -> u64 {
+        self.hits[t.to_u8() as usize].load(Ordering::Relaxed)
+    }
+
+    /// number of gets answered with a miss, including the rejected ones
+    pub fn misses(&self, t: UnityFileType) -> u64 {
+        self.misses[t.to_u8() as usize].load(Ordering::Relaxed)
+    }
+
+    /// file bytes sent by gets
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// file bytes received by puts, including the rejected and discarded ones
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// number of puts rejected by the handler, by the name of the error
+    pub fn put_rejections(&self) -> BTreeMap<&'static str, u64> {
+        self.put_rejections.lock().unwrap().clone()
+    }
+
+    /// number of transactions started and not yet committed or cancelled
+    pub fn open_transactions(&self) -> u64 {
+        self.open_transactions.load(Ordering::Relaxed)
+    }
+
+    /// number of transactions committed by the handler
+    pub fn transactions_committed(&self) -> u64 {
+        self.transactions_committed.load(Ordering::Relaxed)
+    }
+
+    /// number of transactions cancelled, replaced by another one, or failed to commit
+    pub fn transactions_cancelled(&self) -> u64 {
+        self.transactions_cancelled.load(Ordering::Relaxed)
+    }
+
+    /// time of `Handler::get`
+    pub fn get_latency(&self) -> &Histogram {
+        &self.get_latency
+    }
+
+    /// time of `Handler::put`, including receiving the file
+    pub fn put_latency(&self) -> &Histogram {
+        &self.put_latency
+    }
+
+    /// time of `Handler::end_transaction`
+    pub fn end_transaction_latency(&self) -> &Histogram {
+        &self.end_transaction_latency
+    }
+
+    pub fn storage_usage(&self) -> Option<StorageUsage> {
+        *self.storage_usage.lock().unwrap()
+    }
+
+    pub fn set_storage_usage(&self, usage: Option<StorageUsage>) {
+        *self.storage_usage.lock().unwrap() = usage;
+    }
+
+    pub fn connection_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a connection as active until the guard is dropped
+    pub fn session_started(&self) -> ActiveSession<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveSession(self)
+    }
+}
+
+impl CommandObserver for Stats {
+    fn on_command(&self, record: CommandRecord) {
+        match record.kind {
+            CommandKind::Get => {
+                if let Some(t) = record.file_type {
+                    let counter = match record.outcome {
+                        CommandOutcome::Hit => &self.hits,
+                        _ => &self.misses,
+                    };
+                    counter[t.to_u8() as usize].fetch_add(1, Ordering::Relaxed);
+                }
+                self.bytes_sent.fetch_add(record.bytes, Ordering::Relaxed);
+                if let Some(time) = record.handler_time {
+                    self.get_latency.observe(time);
+                }
+            }
+            CommandKind::Put => {
+                self.bytes_received.fetch_add(record.bytes, Ordering::Relaxed);
+                if let Some(error) = record.outcome.error() {
+                    *self.put_rejections.lock().unwrap().entry(error).or_default() += 1;
+                }
+                if let Some(time) = record.handler_time {
+                    self.put_latency.observe(time);
+                }
+            }
+            CommandKind::EndTransaction => {
+                if let Some(time) = record.handler_time {
+                    self.end_transaction_latency.observe(time);
+                }
+            }
+            CommandKind::StartTransaction => {}
+        }
+    }
+
+    fn on_transaction_opened(&self) {
+        self.open_transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_transaction_closed(&self, committed: bool) {
+        self.open_transactions.fetch_sub(1, Ordering::Relaxed);
+        let counter = if committed { &self.transactions_committed } else { &self.transactions_cancelled };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// one line summary for the log
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let types = [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource];
+        let hits: u64 = types.iter().map(|t| self.hits(*t)).sum();
+        let misses: u64 = types.iter().map(|t| self.misses(*t)).sum();
+        let hit_rate = if hits + misses == 0 { 0.0 } else { hits as f64 * 100.0 / (hits + misses) as f64 };
+        let puts_rejected: u64 = self.put_rejections().values().sum();
+        write!(f, "connections accepted: {}, active: {}, rejected: {}, errors: {}; gets: {}, hit rate: {:.1}%; bytes sent: {}, received: {}; transactions committed: {}, cancelled: {}, open: {}; puts rejected: {}",
+               self.accepted(), self.active(), self.rejected(), self.errors(),
+               hits + misses, hit_rate, self.bytes_sent(), self.bytes_received(),
+               self.transactions_committed(), self.transactions_cancelled(), self.open_transactions(), puts_rejected)?;
+        if let Some(usage) = self.storage_usage() {
+            write!(f, "; storage files: {}, bytes: {}", usage.files, usage.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decrease the active connections on drop
+#[derive(Debug)]
+pub struct ActiveSession<'a>(&'a Stats);
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Durations counted in [`LATENCY_BUCKETS`]
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// not cumulative. the last one is over the largest bound.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let i = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// number of durations up to each bound of [`LATENCY_BUCKETS`], then the total count
+    pub fn cumulative_counts(&self) -> Vec<u64> {
+        let mut total = 0;
+        self.buckets.iter().map(|bucket| {
+            total += bucket.load(Ordering::Relaxed);
+            total
+        }).collect()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn record(kind: CommandKind, file_type: UnityFileType, outcome: CommandOutcome, bytes: u64) -> CommandRecord {
+        CommandRecord {
+            kind,
+            file_type: Some(file_type),
+            guid: None,
+            hash: None,
+            outcome,
+            bytes,
+            started_at: SystemTime::now(),
+            duration: Duration::ZERO,
+            handler_time: None,
+        }
+    }
+
+    #[test]
+    fn summary_of_the_observed_sessions() {
+        let stats = Stats::new();
+        assert_eq!(stats.to_string(), "connections accepted: 0, active: 0, rejected: 0, errors: 0; gets: 0, hit rate: 0.0%; \
+            bytes sent: 0, received: 0; transactions committed: 0, cancelled: 0, open: 0; puts rejected: 0");
+
+        for _ in 0..3 {
+            stats.connection_accepted();
+        }
+        stats.connection_rejected();
+        stats.session_error();
+        let _session = stats.session_started();
+        for t in [UnityFileType::Asset, UnityFileType::Info, UnityFileType::Resource] {
+            stats.on_command(record(CommandKind::Get, t, CommandOutcome::Hit, 100));
+        }
+        stats.on_command(record(CommandKind::Get, UnityFileType::Asset, CommandOutcome::Miss, 0));
+        stats.on_command(record(CommandKind::Put, UnityFileType::Asset, CommandOutcome::Ok, 50));
+        stats.on_command(record(CommandKind::Put, UnityFileType::Info, CommandOutcome::Rejected("FileTooLarge"), 0));
+        stats.on_command(record(CommandKind::Put, UnityFileType::Resource, CommandOutcome::Rejected("HandlerError"), 0));
+        // discarded puts are not rejected
+        stats.on_command(record(CommandKind::Put, UnityFileType::Resource, CommandOutcome::Discarded, 0));
+        for _ in 0..3 {
+            stats.on_transaction_opened();
+        }
+        stats.on_transaction_closed(true);
+        stats.on_transaction_closed(false);
+        assert_eq!(stats.to_string(), "connections accepted: 3, active: 1, rejected: 1, errors: 1; gets: 4, hit rate: 75.0%; \
+            bytes sent: 300, received: 50; transactions committed: 1, cancelled: 1, open: 1; puts rejected: 2");
+
+        stats.set_storage_usage(Some(StorageUsage { files: 4, bytes: 350 }));
+        assert!(stats.to_string().ends_with("; puts rejected: 2; storage files: 4, bytes: 350"));
+    }
+
+    #[test]
+    fn cumulative_counts() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.cumulative_counts(), vec![0; LATENCY_BUCKETS.len() + 1]);
+        // a bound is in its own bucket
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(60));
+        let counts = histogram.cumulative_counts();
+        assert_eq!(counts.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(&counts[..5], &[0, 1, 1, 3, 3]);
+        assert_eq!(counts[LATENCY_BUCKETS.len() - 1], 3);
+        assert_eq!(counts[LATENCY_BUCKETS.len()], 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(60_007));
+    }
+}